@@ -0,0 +1,45 @@
+const API_HOST = import.meta.env.VITE_API_HOST;
+
+// How often the control lock is checked, it has no events of its own.
+const POLL_INTERVAL = 2000;
+
+/**
+ * The control lock as reported by `/api/state`. Stays null without authentication.
+ *
+ * @type {{ value: null | { role: "viewer" | "operator", locked: boolean, holder: boolean, requested: boolean } }}
+ */
+export const control = $state({ value: null });
+
+async function poll() {
+  try {
+    const response = await fetch(`${API_HOST}/api/state`);
+    if (response.ok) {
+      control.value = (await response.json()).control ?? null;
+    }
+  } catch (err) {
+    console.warn(err);
+  }
+}
+
+poll();
+setInterval(poll, POLL_INTERVAL);
+
+/** Whether this session may record and change the configuration. */
+export function canControl() {
+  const value = control.value;
+  return (
+    !value || (value.role === "operator" && (!value.locked || value.holder))
+  );
+}
+
+/** Takes the lock, or asks its holder for it unless `force` is set. */
+export async function takeControl(force = false) {
+  const query = force ? "?force=true" : "";
+  await fetch(`${API_HOST}/api/lock${query}`, { method: "POST" });
+  await poll();
+}
+
+export async function releaseControl() {
+  await fetch(`${API_HOST}/api/lock`, { method: "DELETE" });
+  await poll();
+}
@@ -0,0 +1,22 @@
+const API_HOST = import.meta.env.VITE_API_HOST;
+
+// A single connection shared by all components.
+const source = new EventSource(`${API_HOST}/api/events`);
+
+/**
+ * Calls `callback` with the data of every camera event of the given type.
+ * Returns a function that removes the subscription again.
+ *
+ * @param {"state" | "configuration" | "recording" | "error" | "gallery-added" | "gallery-removed" | "whip" | "srt"} type
+ * @param {(data: any) => void} callback
+ */
+export function subscribe(type, callback) {
+  const listener = (message) => {
+    const event = JSON.parse(message.data);
+    if (event.type === type) {
+      callback(event.data);
+    }
+  };
+  source.addEventListener("message", listener);
+  return () => source.removeEventListener("message", listener);
+}
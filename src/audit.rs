@@ -0,0 +1,402 @@
+use std::io::SeekFrom;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, Query, Request, State},
+    http::{HeaderMap, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::auth::{Auth, LockTakeover, Role};
+
+/// Request bodies larger than this are logged without their content.
+const MAX_LOGGED_BODY: usize = 64 * 1024;
+/// What axum accepts by default.
+const MAX_BODY: usize = 2 * 1024 * 1024;
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+/// The log is read backwards in chunks of this size, so a page doesn't take the whole file.
+const READ_CHUNK: u64 = 64 * 1024;
+
+/// Paths whose request bodies must not end up in the log.
+const SECRET_BODIES: [&str; 1] = ["/api/login"];
+/// Fields of request bodies that are replaced before logging, by path.
+const SECRET_FIELDS: [(&str, &str); 2] = [("/api/whip", "token"), ("/api/srt", "passphrase")];
+
+/// One request that could have changed something, over HTTP or the control data channel.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct AuditEntry {
+    pub timestamp: String,
+    /// Unknown for requests over the control data channel of a standalone signalling server.
+    pub client: Option<SocketAddr>,
+    /// None without authentication, or when the request had no valid credentials.
+    pub role: Option<Role>,
+    /// Tells apart operators that share a role. The start of the session id.
+    pub session: Option<String>,
+    pub method: String,
+    pub path: String,
+    /// The request body, when it's JSON.
+    pub body: Option<serde_json::Value>,
+    pub status: u16,
+    /// Set when the request forced the control lock away from its holder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub takeover: Option<LockTakeover>,
+}
+
+/// Appends entries to a JSON lines file, one line per request.
+#[derive(Clone)]
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    sender: mpsc::Sender<AuditEntry>,
+}
+
+#[derive(Deserialize)]
+struct Page {
+    /// How many of the newest entries to skip.
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct Entries {
+    /// Whether there are older entries than these.
+    more: bool,
+    /// Newest first.
+    entries: Vec<AuditEntry>,
+}
+
+impl AuditLog {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<AuditEntry>(64);
+
+        let file_path = path.clone();
+        tokio::spawn(async move {
+            let mut file = match tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&file_path)
+                .await
+            {
+                Ok(file) => file,
+                Err(err) => {
+                    warn!(
+                        "Failed to open the audit log {}: {}",
+                        file_path.display(),
+                        err
+                    );
+                    return;
+                }
+            };
+
+            while let Some(entry) = receiver.recv().await {
+                let Ok(mut line) = serde_json::to_string(&entry) else {
+                    continue;
+                };
+                line.push('\n');
+                if let Err(err) = file.write_all(line.as_bytes()).await {
+                    warn!("Failed to write the audit log: {}", err);
+                }
+                let _ = file.flush().await;
+            }
+        });
+
+        Self { path, sender }
+    }
+
+    pub(crate) async fn append(&self, entry: AuditEntry) {
+        let _ = self.sender.send(entry).await;
+    }
+
+    async fn entries(&self, page: Page) -> std::io::Result<Entries> {
+        let limit = page.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+        let mut entries = Entries {
+            more: false,
+            entries: Vec::new(),
+        };
+        let mut file = match tokio::fs::File::open(&self.path).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+            Err(err) => return Err(err),
+        };
+
+        let mut position = file.metadata().await?.len();
+        // The start of the line the previous chunk began in the middle of.
+        let mut rest = Vec::new();
+        let mut skipped = 0;
+        while position > 0 {
+            let size = READ_CHUNK.min(position);
+            position -= size;
+            let mut chunk = vec![0; size as usize];
+            file.seek(SeekFrom::Start(position)).await?;
+            file.read_exact(&mut chunk).await?;
+            chunk.append(&mut rest);
+
+            let mut lines = chunk.split(|byte| *byte == b'\n');
+            // Only complete once the start of the file is reached.
+            let first = lines.next().unwrap_or_default();
+            let lines: Vec<&[u8]> = lines.collect();
+            let complete = if position == 0 { Some(first) } else { None };
+            for line in lines.into_iter().rev().chain(complete) {
+                if line.is_empty() {
+                    continue;
+                }
+                if skipped < page.offset {
+                    skipped += 1;
+                } else if entries.entries.len() == limit {
+                    entries.more = true;
+                    return Ok(entries);
+                } else if let Ok(entry) = serde_json::from_slice(line) {
+                    entries.entries.push(entry);
+                }
+            }
+            if position > 0 {
+                rest = first.to_vec();
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Serves the log to operators, paginated with `offset` and `limit`.
+    pub(crate) fn router(&self, auth: Option<Auth>) -> Router {
+        let log = self.clone();
+        Router::new().route(
+            "/api/audit",
+            get(
+                |headers: HeaderMap, uri: Uri, Query(page): Query<Page>| async move {
+                    // It shows what everyone did, and from where.
+                    let operator = auth.as_ref().is_none_or(|auth| {
+                        auth.identify(&headers, &uri)
+                            .is_some_and(|identity| identity.role == Role::Operator)
+                    });
+                    if !operator {
+                        return StatusCode::FORBIDDEN.into_response();
+                    }
+                    match log.entries(page).await {
+                        Ok(entries) => Json(entries).into_response(),
+                        Err(err) => {
+                            (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response()
+                        }
+                    }
+                },
+            ),
+        )
+    }
+}
+
+/// Middleware that logs every request that isn't only reading.
+pub(crate) async fn record(
+    State((log, auth)): State<(AuditLog, Option<Auth>)>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    ) {
+        return next.run(request).await;
+    }
+
+    let identity = auth
+        .as_ref()
+        .and_then(|auth| auth.identify(request.headers(), request.uri()));
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+
+    let (parts, body) = request.into_parts();
+    let (request, body) = if SECRET_BODIES.contains(&path.as_str()) {
+        (Request::from_parts(parts, body), None)
+    } else {
+        match to_bytes(body, MAX_BODY).await {
+            Ok(bytes) => {
+                let json = (bytes.len() <= MAX_LOGGED_BODY)
+                    .then(|| serde_json::from_slice(&bytes).ok())
+                    .flatten()
+                    .map(|json| redact(&path, json));
+                (Request::from_parts(parts, Body::from(bytes)), json)
+            }
+            // The body is gone by now, so is the request.
+            Err(_) => return StatusCode::PAYLOAD_TOO_LARGE.into_response(),
+        }
+    };
+
+    let response = next.run(request).await;
+
+    let entry = AuditEntry {
+        timestamp: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+        client: Some(client),
+        role: identity.as_ref().map(|identity| identity.role),
+        session: identity
+            .and_then(|identity| identity.session)
+            .map(|session| session.chars().take(8).collect()),
+        method,
+        path,
+        body,
+        status: response.status().as_u16(),
+        takeover: response.extensions().get::<LockTakeover>().cloned(),
+    };
+    log.append(entry).await;
+
+    response
+}
+
+/// Replaces the secret fields of a request body.
+fn redact(path: &str, mut body: serde_json::Value) -> serde_json::Value {
+    if let Some(body) = body.as_object_mut() {
+        for (_, field) in SECRET_FIELDS.iter().filter(|(secret, _)| *secret == path) {
+            if let Some(value) = body.get_mut(*field) {
+                if !value.is_null() {
+                    *value = "***".into();
+                }
+            }
+        }
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_secret_fields() {
+        let whip = serde_json::json!({ "url": "https://example.com/whip", "token": "secret" });
+        assert_eq!(
+            redact("/api/whip", whip.clone()),
+            serde_json::json!({ "url": "https://example.com/whip", "token": "***" })
+        );
+        // Only on the path the field is secret on.
+        assert_eq!(
+            redact("/api/configuration", whip),
+            serde_json::json!({ "url": "https://example.com/whip", "token": "secret" })
+        );
+
+        let srt = serde_json::json!({ "address": "0.0.0.0:9000", "passphrase": null });
+        assert_eq!(redact("/api/srt", srt.clone()), srt);
+        assert_eq!(
+            redact("/api/srt", serde_json::json!(true)),
+            serde_json::json!(true)
+        );
+    }
+
+    fn entry(index: usize) -> AuditEntry {
+        AuditEntry {
+            timestamp: String::new(),
+            client: None,
+            role: None,
+            session: None,
+            method: "POST".into(),
+            path: format!("/api/{index}"),
+            body: None,
+            status: 200,
+            takeover: None,
+        }
+    }
+
+    /// A log in a file of its own, with `content` in it.
+    async fn log(name: &str, content: &[u8]) -> AuditLog {
+        let path = std::env::temp_dir().join(format!("audit-{}-{name}.jsonl", std::process::id()));
+        tokio::fs::write(&path, content).await.unwrap();
+        AuditLog {
+            path,
+            sender: mpsc::channel(1).0,
+        }
+    }
+
+    async fn paths(log: &AuditLog, offset: usize, limit: usize) -> (Vec<String>, bool) {
+        let entries = log
+            .entries(Page {
+                offset,
+                limit: Some(limit),
+            })
+            .await
+            .unwrap();
+        let paths = entries
+            .entries
+            .into_iter()
+            .map(|entry| entry.path)
+            .collect();
+        (paths, entries.more)
+    }
+
+    #[tokio::test]
+    async fn pages_backwards_across_chunks() {
+        // Enough entries that lines span the chunks the file is read in.
+        let count = 2 * READ_CHUNK as usize / 100;
+        let mut content = Vec::new();
+        for index in 0..count {
+            serde_json::to_writer(&mut content, &entry(index)).unwrap();
+            content.push(b'\n');
+        }
+        assert!(content.len() as u64 > 2 * READ_CHUNK);
+        let log = log("chunks", &content).await;
+
+        let (newest, more) = paths(&log, 0, 2).await;
+        assert_eq!(
+            newest,
+            [format!("/api/{}", count - 1), format!("/api/{}", count - 2)]
+        );
+        assert!(more);
+
+        // Every entry exactly once, newest first.
+        let mut all = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (page, more) = paths(&log, offset, 100).await;
+            offset += page.len();
+            all.extend(page);
+            if !more {
+                break;
+            }
+        }
+        let expected: Vec<String> = (0..count)
+            .rev()
+            .map(|index| format!("/api/{index}"))
+            .collect();
+        assert_eq!(all, expected);
+
+        // A page that ends with the log doesn't claim there is more.
+        assert!(!paths(&log, count - 3, 3).await.1);
+        assert_eq!(paths(&log, count, 3).await, (vec![], false));
+
+        tokio::fs::remove_file(&log.path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn skips_a_partly_written_last_line() {
+        let mut content = serde_json::to_vec(&entry(0)).unwrap();
+        content.push(b'\n');
+        content.extend_from_slice(br#"{"timestamp":"","cli"#);
+        let log = log("partial", &content).await;
+
+        assert_eq!(
+            paths(&log, 0, 10).await,
+            (vec!["/api/0".to_string()], false)
+        );
+
+        tokio::fs::remove_file(&log.path).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_empty_or_missing_log_has_no_entries() {
+        let log = log("empty", b"").await;
+        assert_eq!(paths(&log, 0, 10).await, (vec![], false));
+
+        tokio::fs::remove_file(&log.path).await.unwrap();
+        assert_eq!(paths(&log, 0, 10).await, (vec![], false));
+    }
+}
@@ -0,0 +1,569 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+const SESSION_COOKIE: &str = "capture_session";
+/// The lock is freed when its holder hasn't been heard from for this long. The web UI
+/// polls the state every two seconds, so this only happens once it's gone.
+const LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Sessions end when they haven't been used for this long.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+/// Sessions end this long after logging in, even while they are used.
+const SESSION_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Requests that viewers may make even though they change something, because they are
+/// about watching rather than driving the camera.
+const VIEWER_ROUTES: [&str; 1] = ["/api/whep"];
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Role {
+    /// Sees the state and the streams.
+    Viewer,
+    /// May also record and change the configuration.
+    Operator,
+    /// webrtcsink, which registers as producer with the signalling and otherwise
+    /// sees what viewers see.
+    Producer,
+}
+
+/// Who made a request.
+#[derive(Clone)]
+pub(crate) struct Identity {
+    pub role: Role,
+    /// Scripts using a bearer token have none, and can't take the control lock.
+    pub session: Option<String>,
+}
+
+/// Checks requests against the shared tokens.
+/// The web UI trades one for a session cookie, scripts send it as bearer token
+/// and WebSocket clients that can't set headers pass it as `token` query parameter.
+#[derive(Clone)]
+pub(crate) struct Auth {
+    operator_token: Arc<str>,
+    viewer_token: Option<Arc<str>>,
+    /// Minted for webrtcsink's signalling connection, so it doesn't need the operator token.
+    producer_token: Arc<str>,
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    lock: Arc<Mutex<ControlLock>>,
+}
+
+/// A login of the web UI.
+struct Session {
+    role: Role,
+    created: Instant,
+    last_seen: Instant,
+}
+
+impl Session {
+    fn new(role: Role) -> Self {
+        let now = Instant::now();
+        Self {
+            role,
+            created: now,
+            last_seen: now,
+        }
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        now.duration_since(self.last_seen) > SESSION_IDLE_TIMEOUT
+            || now.duration_since(self.created) > SESSION_LIFETIME
+    }
+}
+
+/// Lets one operator session drive the camera while others watch.
+#[derive(Default)]
+struct ControlLock {
+    holder: Option<String>,
+    /// When the holder last made a request.
+    last_seen: Option<Instant>,
+    /// Another operator session that asked for control.
+    requested_by: Option<String>,
+}
+
+/// The control lock as seen by the session asking.
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub(crate) struct Control {
+    pub role: Role,
+    pub locked: bool,
+    /// Whether the asking session holds the lock.
+    pub holder: bool,
+    /// Whether another session asked for the lock.
+    pub requested: bool,
+}
+
+/// A forced takeover of the control lock, handed to the audit log with the response.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) struct LockTakeover {
+    /// The start of the session id of the holder the lock was taken from, if it had one.
+    pub previous_holder: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TakeLock {
+    /// Takes the lock from its holder instead of asking for it.
+    #[serde(default)]
+    force: bool,
+}
+
+#[derive(Deserialize)]
+struct Login {
+    password: String,
+}
+
+#[derive(Serialize)]
+struct SessionStatus {
+    authenticated: bool,
+    role: Option<Role>,
+}
+
+impl Auth {
+    pub(crate) fn new(operator_token: &str, viewer_token: Option<&str>) -> Self {
+        Self {
+            operator_token: operator_token.into(),
+            viewer_token: viewer_token.map(Into::into),
+            producer_token: format!("{:032x}", rand::random::<u128>()).into(),
+            sessions: Default::default(),
+            lock: Default::default(),
+        }
+    }
+
+    fn role_of_token(&self, token: &str) -> Option<Role> {
+        if constant_time_eq(token.as_bytes(), self.operator_token.as_bytes()) {
+            Some(Role::Operator)
+        } else if self
+            .viewer_token
+            .as_ref()
+            .is_some_and(|viewer| constant_time_eq(token.as_bytes(), viewer.as_bytes()))
+        {
+            Some(Role::Viewer)
+        } else if constant_time_eq(token.as_bytes(), self.producer_token.as_bytes()) {
+            Some(Role::Producer)
+        } else {
+            None
+        }
+    }
+
+    /// Lets webrtcsink register as producer, which no other role may.
+    #[cfg(feature = "signalling")]
+    pub(crate) fn producer_token(&self) -> &str {
+        &self.producer_token
+    }
+
+    /// The sessions that haven't expired, the others are dropped first.
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let now = Instant::now();
+        sessions.retain(|_, session| !session.expired(now));
+        sessions
+    }
+
+    /// The role of a session that hasn't expired, which keeps it from going idle.
+    fn use_session(&self, session: &str) -> Option<Role> {
+        let mut sessions = self.sessions();
+        let session = sessions.get_mut(session)?;
+        session.last_seen = Instant::now();
+        Some(session.role)
+    }
+
+    pub(crate) fn identify(&self, headers: &HeaderMap, uri: &Uri) -> Option<Identity> {
+        if let Some(session) = session(headers) {
+            if let Some(role) = self.use_session(session) {
+                self.seen(session);
+                return Some(Identity {
+                    role,
+                    session: Some(session.to_string()),
+                });
+            }
+        }
+
+        let bearer = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let query = uri
+            .query()
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .filter_map(|pair| pair.strip_prefix("token="));
+        bearer
+            .into_iter()
+            .chain(query)
+            .find_map(|token| self.role_of_token(token))
+            .map(|role| Identity {
+                role,
+                session: None,
+            })
+    }
+
+    /// The identity as it is now, None once its session has ended.
+    pub(crate) fn refresh(&self, identity: &Identity) -> Option<Identity> {
+        let Some(session) = &identity.session else {
+            return Some(identity.clone());
+        };
+        let role = self.use_session(session)?;
+        self.seen(session);
+        Some(Identity {
+            role,
+            session: Some(session.clone()),
+        })
+    }
+
+    /// Keeps the lock of a session that is still around.
+    fn seen(&self, session: &str) {
+        let mut lock = self.lock.lock().unwrap();
+        if lock.holder.as_deref() == Some(session) {
+            lock.last_seen = Some(Instant::now());
+        }
+    }
+
+    /// The control lock, freed first if its holder has gone quiet or logged out.
+    fn current_lock(&self) -> std::sync::MutexGuard<'_, ControlLock> {
+        let mut lock = self.lock.lock().unwrap();
+        if let Some(holder) = &lock.holder {
+            let idle = lock
+                .last_seen
+                .is_none_or(|last_seen| last_seen.elapsed() > LOCK_TIMEOUT);
+            if idle || !self.sessions().contains_key(holder) {
+                *lock = ControlLock::default();
+            }
+        }
+        lock
+    }
+
+    /// Whether the request may change something, which takes an operator and,
+    /// while another session holds the control lock, that session.
+    pub(crate) fn may_control(&self, identity: &Identity) -> bool {
+        if identity.role != Role::Operator {
+            return false;
+        }
+        match &self.current_lock().holder {
+            Some(holder) => identity.session.as_ref() == Some(holder),
+            None => true,
+        }
+    }
+
+    /// The control lock for `/api/state`.
+    pub(crate) fn control(&self, headers: &HeaderMap, uri: &Uri) -> Option<Control> {
+        let identity = self.identify(headers, uri)?;
+        let lock = self.current_lock();
+        let holder = lock.holder.is_some() && lock.holder == identity.session;
+        Some(Control {
+            role: identity.role,
+            locked: lock.holder.is_some(),
+            holder,
+            requested: holder && lock.requested_by.is_some(),
+        })
+    }
+
+    /// Takes the lock if it's free, or asks its holder for it otherwise.
+    /// Forcing it takes the lock regardless, and frees it for a bearer token,
+    /// so an operator can always get back in. Forced takeovers are returned for the audit log.
+    fn take_lock(&self, identity: &Identity, force: bool) -> (StatusCode, Option<LockTakeover>) {
+        if identity.role != Role::Operator {
+            return (StatusCode::FORBIDDEN, None);
+        }
+
+        let mut lock = self.current_lock();
+        let takeover = force.then(|| LockTakeover {
+            previous_holder: lock
+                .holder
+                .as_ref()
+                .filter(|holder| identity.session.as_ref() != Some(*holder))
+                .map(|holder| holder.chars().take(8).collect()),
+        });
+        let Some(session) = identity.session.clone() else {
+            if !force {
+                return (StatusCode::BAD_REQUEST, None);
+            }
+            *lock = ControlLock::default();
+            return (StatusCode::NO_CONTENT, takeover);
+        };
+        match &lock.holder {
+            Some(holder) if *holder != session && !force => {
+                lock.requested_by = Some(session);
+                (StatusCode::CONFLICT, None)
+            }
+            _ => {
+                *lock = ControlLock {
+                    holder: Some(session),
+                    last_seen: Some(Instant::now()),
+                    requested_by: None,
+                };
+                (StatusCode::NO_CONTENT, takeover)
+            }
+        }
+    }
+
+    fn release_lock(&self, session: &str) {
+        let mut lock = self.lock.lock().unwrap();
+        if lock.holder.as_deref() == Some(session) {
+            *lock = ControlLock::default();
+        } else if lock.requested_by.as_deref() == Some(session) {
+            lock.requested_by = None;
+        }
+    }
+
+    /// Login, logout and the control lock. These check the session themselves.
+    pub(crate) fn router(&self) -> Router {
+        let login = self.clone();
+        let logout = self.clone();
+        let status = self.clone();
+        let take = self.clone();
+        let release = self.clone();
+
+        Router::new()
+            .route(
+                "/api/login",
+                post(|Json(payload): Json<Login>| async move {
+                    let Some(role) = login.role_of_token(&payload.password) else {
+                        return StatusCode::UNAUTHORIZED.into_response();
+                    };
+                    let session = format!(
+                        "{:032x}{:032x}",
+                        rand::random::<u128>(),
+                        rand::random::<u128>()
+                    );
+                    login
+                        .sessions()
+                        .insert(session.clone(), Session::new(role));
+                    (
+                        StatusCode::NO_CONTENT,
+                        [(
+                            header::SET_COOKIE,
+                            format!(
+                                "{SESSION_COOKIE}={session}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+                                SESSION_LIFETIME.as_secs()
+                            ),
+                        )],
+                    )
+                        .into_response()
+                }),
+            )
+            .route(
+                "/api/logout",
+                post(|headers: HeaderMap| async move {
+                    if let Some(session) = session(&headers) {
+                        logout.sessions.lock().unwrap().remove(session);
+                        logout.release_lock(session);
+                    }
+                    (
+                        StatusCode::NO_CONTENT,
+                        [(
+                            header::SET_COOKIE,
+                            format!(
+                                "{SESSION_COOKIE}=; Path=/; HttpOnly; SameSite=Strict; Max-Age=0"
+                            ),
+                        )],
+                    )
+                }),
+            )
+            .route(
+                "/api/session",
+                get(|headers: HeaderMap, uri: Uri| async move {
+                    let role = status
+                        .identify(&headers, &uri)
+                        .map(|identity| identity.role);
+                    Json(SessionStatus {
+                        authenticated: role.is_some(),
+                        role,
+                    })
+                }),
+            )
+            .route(
+                "/api/lock",
+                post(
+                    |headers: HeaderMap, uri: Uri, Query(query): Query<TakeLock>| async move {
+                        let Some(identity) = take.identify(&headers, &uri) else {
+                            return StatusCode::UNAUTHORIZED.into_response();
+                        };
+                        match take.take_lock(&identity, query.force) {
+                            (status, Some(takeover)) => {
+                                (status, Extension(takeover)).into_response()
+                            }
+                            (status, None) => status.into_response(),
+                        }
+                    },
+                )
+                .delete(|headers: HeaderMap| async move {
+                    match session(&headers) {
+                        Some(session) => {
+                            release.release_lock(session);
+                            StatusCode::NO_CONTENT
+                        }
+                        None => StatusCode::UNAUTHORIZED,
+                    }
+                }),
+            )
+    }
+}
+
+/// Middleware that turns away requests without a valid token or session,
+/// and those that would change something without being allowed to.
+pub(crate) async fn require(State(auth): State<Auth>, request: Request, next: Next) -> Response {
+    let Some(identity) = auth.identify(request.headers(), request.uri()) else {
+        return StatusCode::UNAUTHORIZED.into_response();
+    };
+
+    let reading = matches!(*request.method(), Method::GET | Method::HEAD);
+    let watching = VIEWER_ROUTES
+        .iter()
+        .any(|route| request.uri().path().starts_with(route));
+    if reading || watching {
+        return next.run(request).await;
+    }
+
+    if identity.role != Role::Operator {
+        StatusCode::FORBIDDEN.into_response()
+    } else if !auth.may_control(&identity) {
+        StatusCode::LOCKED.into_response()
+    } else {
+        next.run(request).await
+    }
+}
+
+fn session(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+/// Doesn't give away how much of the token was right by how long the comparison took.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Allows any origin without a list, like before there was one.
+pub(crate) fn cors(origins: &[HeaderValue]) -> CorsLayer {
+    if origins.is_empty() {
+        return CorsLayer::permissive();
+    }
+
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::list(origins.iter().cloned()))
+        .allow_methods([Method::GET, Method::POST, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+        // WHEP players need it to end their session.
+        .expose_headers([header::LOCATION])
+        .allow_credentials(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_map_to_their_roles() {
+        let auth = Auth::new("operator", Some("viewer"));
+
+        assert_eq!(auth.role_of_token("operator"), Some(Role::Operator));
+        assert_eq!(auth.role_of_token("viewer"), Some(Role::Viewer));
+        assert_eq!(
+            auth.role_of_token(auth.producer_token.as_ref()),
+            Some(Role::Producer)
+        );
+        assert_eq!(auth.role_of_token("operato"), None);
+        assert_eq!(auth.role_of_token(""), None);
+    }
+
+    fn login(auth: &Auth, session: &str, role: Role) -> Identity {
+        auth.sessions().insert(session.into(), Session::new(role));
+        Identity {
+            role,
+            session: Some(session.into()),
+        }
+    }
+
+    #[test]
+    fn sessions_expire_when_idle_and_after_their_lifetime() {
+        let session = Session::new(Role::Operator);
+        let now = session.created;
+        assert!(!session.expired(now));
+        assert!(!session.expired(now + SESSION_IDLE_TIMEOUT));
+        assert!(session.expired(now + SESSION_IDLE_TIMEOUT + Duration::from_secs(1)));
+
+        let used = Session {
+            last_seen: now + SESSION_LIFETIME,
+            ..Session::new(Role::Operator)
+        };
+        assert!(!used.expired(now + SESSION_LIFETIME));
+        assert!(used.expired(used.created + SESSION_LIFETIME + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn operators_take_the_lock_in_turns() {
+        let auth = Auth::new("operator", Some("viewer"));
+        let first = login(&auth, "first", Role::Operator);
+        let second = login(&auth, "second", Role::Operator);
+        let viewer = login(&auth, "viewer", Role::Viewer);
+
+        assert!(auth.may_control(&first));
+        assert_eq!(auth.take_lock(&viewer, false).0, StatusCode::FORBIDDEN);
+        assert_eq!(auth.take_lock(&first, false).0, StatusCode::NO_CONTENT);
+        assert!(auth.may_control(&first));
+        assert!(!auth.may_control(&second));
+
+        // Asking leaves the lock with its holder, forcing takes it.
+        assert_eq!(auth.take_lock(&second, false).0, StatusCode::CONFLICT);
+        assert_eq!(auth.current_lock().requested_by.as_deref(), Some("second"));
+        assert!(!auth.may_control(&second));
+        let takeover = LockTakeover {
+            previous_holder: Some("first".into()),
+        };
+        assert_eq!(
+            auth.take_lock(&second, true),
+            (StatusCode::NO_CONTENT, Some(takeover))
+        );
+        assert!(auth.may_control(&second));
+        assert!(!auth.may_control(&first));
+
+        // Scripts without a session can only free it.
+        let script = Identity {
+            role: Role::Operator,
+            session: None,
+        };
+        assert_eq!(auth.take_lock(&script, false).0, StatusCode::BAD_REQUEST);
+        let takeover = LockTakeover {
+            previous_holder: Some("second".into()),
+        };
+        assert_eq!(
+            auth.take_lock(&script, true),
+            (StatusCode::NO_CONTENT, Some(takeover))
+        );
+        assert!(auth.may_control(&first));
+    }
+
+    #[test]
+    fn the_lock_is_freed_when_its_holder_is_gone() {
+        let auth = Auth::new("operator", None);
+        let first = login(&auth, "first", Role::Operator);
+        let second = login(&auth, "second", Role::Operator);
+
+        assert_eq!(auth.take_lock(&first, false).0, StatusCode::NO_CONTENT);
+        auth.lock.lock().unwrap().last_seen =
+            Instant::now().checked_sub(LOCK_TIMEOUT + Duration::from_secs(1));
+        assert!(auth.may_control(&second));
+        assert!(auth.current_lock().holder.is_none());
+
+        assert_eq!(auth.take_lock(&first, false).0, StatusCode::NO_CONTENT);
+        auth.sessions().remove("first");
+        assert!(auth.may_control(&second));
+        assert_eq!(auth.refresh(&first).map(|identity| identity.role), None);
+    }
+}
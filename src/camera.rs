@@ -1,16 +1,53 @@
+use std::collections::BTreeSet;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use axum::body::Bytes;
 use color_eyre::Result;
-use configuration::VideoCodec;
+use configuration::{OutputMode, VideoCodec, WebRtcTracks};
 use gstreamer::{event, prelude::*, Element, MessageType};
 use gstreamer::{ElementFactory, Pipeline, State};
 use serde::Serialize;
 use time::{format_description, OffsetDateTime};
-use tokio::sync::mpsc;
-use tracing::info;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
+use tokio::time::MissedTickBehavior;
+use tracing::{info, warn};
+
+use crate::gallery;
 
 mod configuration;
-pub use configuration::{Configuration, NullableConfiguration};
+mod encoders;
+mod feed;
+mod hls;
+mod mjpeg;
+#[cfg(feature = "rtsp")]
+mod rtsp;
+mod scopes;
+mod shaders;
+mod snapshot;
+mod srt;
+mod webrtc;
+mod whep;
+mod whip;
+pub use configuration::{Configuration, HlsStream, NullableConfiguration};
+use feed::Feeds;
+pub use hls::hls_dir;
+use hls::HlsOutput;
+#[cfg(feature = "rtsp")]
+pub use rtsp::{RtspServer, RtspSettings};
+pub use scopes::Scopes;
+use snapshot::Snapshots;
+pub use snapshot::{Eye, ImageFormat};
+pub use srt::{
+    parse_passphrase as parse_srt_passphrase, SrtCodec, SrtMode, SrtSettings, SrtStatus,
+    MAX_LATENCY as SRT_MAX_LATENCY,
+};
+use srt::{SrtOutput, SrtState};
+pub use webrtc::{RemoteControl, RemoteRequest, WebRtcSettings};
+pub use whep::{SessionLimit, WhepEndpoint};
+use whip::{WhipConnection, WhipOutput};
+pub use whip::{WhipSettings, WhipStatus};
 
 struct CameraActor {
     receiver: mpsc::Receiver<CameraActorMessage>,
@@ -20,14 +57,61 @@ struct CameraActor {
     /// The current configuration of the camera.
     /// Some fields may be ignored depending on the state of the camera.
     configuration: Configuration,
+    /// Publishes `state` to the handles, so reading it never has to wait for the actor.
+    state_sender: watch::Sender<CameraState>,
+    /// Publishes `configuration` to the handles.
+    configuration_sender: watch::Sender<Configuration>,
+    events: broadcast::Sender<CameraEvent>,
+    /// Updated from the streaming threads of the running pipeline.
+    scopes: Arc<watch::Sender<Scopes>>,
+    /// Updated from the streaming threads of the running pipeline.
+    snapshots: Arc<watch::Sender<Snapshots>>,
+    /// The latest frame of the MJPEG preview.
+    mjpeg: Arc<watch::Sender<Bytes>>,
+    /// Serves WHEP players while the livefeed is running.
+    whep: watch::Sender<Option<Arc<WhepEndpoint>>>,
+    /// Frames of the livefeed for the outputs that run in their own pipeline.
+    feeds: Feeds,
+    whip: Option<WhipOutput>,
+    /// Updated from the streaming threads of the WHIP output.
+    whip_status: Arc<watch::Sender<WhipStatus>>,
+    srt: Option<SrtOutput>,
+    hls: Option<HlsOutput>,
+    /// Updated from the SRT output when it fails or restarts, and with its stats every tick.
+    srt_status: Arc<watch::Sender<SrtStatus>>,
+    /// Applied to webrtcsink whenever the livefeed starts.
+    webrtc: WebRtcSettings,
+    /// The recordings last seen in the gallery directory.
+    gallery: BTreeSet<String>,
+}
+
+/// How often recording stats are published and the gallery is checked for changes.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The overlays of the composed livefeed, drawn in GL memory by the shader and in system
+/// memory by the text overlays.
+struct Hud {
+    shader: Element,
+    timecode: Element,
+    info: Element,
 }
 
 enum Controls {
-    Capture {},
+    Capture {
+        started: Instant,
+        files: [String; 2],
+        hud: Hud,
+    },
     Livefeed {
         left_transform: Element,
         right_transform: Element,
+        left_assist: Element,
+        right_assist: Element,
         glviewconvert: Element,
+        multiview_caps: Element,
+        analysis: Element,
+        hud: Hud,
+        mjpeg_caps: Element,
     },
 }
 
@@ -39,26 +123,371 @@ pub enum CameraState {
     Capture,
 }
 
+#[derive(Clone, PartialEq, Debug, Serialize)]
+pub struct RecordingStats {
+    /// Seconds since the capture was started.
+    pub duration: f64,
+    /// Combined size of the files written so far.
+    pub bytes: u64,
+    pub files: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "kebab-case")]
+pub enum CameraEvent {
+    State(CameraState),
+    Configuration(Configuration),
+    Recording(RecordingStats),
+    Error(String),
+    GalleryAdded(String),
+    GalleryRemoved(String),
+    Whip(WhipStatus),
+    Srt(SrtStatus),
+}
+
 enum CameraActorMessage {
-    StartCapture(),
-    StartLivefeed(),
-    GetState(tokio::sync::oneshot::Sender<CameraState>),
-    GetConfiguration(tokio::sync::oneshot::Sender<Configuration>),
-    SetConfiguration(NullableConfiguration),
+    /// Answered once the capture is running, or with the error that kept it from starting.
+    StartCapture(oneshot::Sender<Result<(), String>>),
+    StartLivefeed(oneshot::Sender<Result<(), String>>),
+    /// Carries the senders of every request that has been coalesced into this one.
+    SetConfiguration(NullableConfiguration, Vec<oneshot::Sender<Configuration>>),
+    SetWhip(WhipSettings, oneshot::Sender<WhipStatus>),
+    /// Stops the SRT output when there are no settings.
+    SetSrt(Option<SrtSettings>, oneshot::Sender<SrtStatus>),
     Shutdown(),
 }
 
 impl CameraActor {
-    fn new(receiver: mpsc::Receiver<CameraActorMessage>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        receiver: mpsc::Receiver<CameraActorMessage>,
+        state_sender: watch::Sender<CameraState>,
+        configuration_sender: watch::Sender<Configuration>,
+        events: broadcast::Sender<CameraEvent>,
+        scopes: watch::Sender<Scopes>,
+        snapshots: watch::Sender<Snapshots>,
+        mjpeg: watch::Sender<Bytes>,
+        whep: watch::Sender<Option<Arc<WhepEndpoint>>>,
+        whip_status: watch::Sender<WhipStatus>,
+        feeds: Feeds,
+        srt_status: watch::Sender<SrtStatus>,
+        webrtc: WebRtcSettings,
+    ) -> Self {
+        let state = *state_sender.borrow();
+        let configuration = *configuration_sender.borrow();
         Self {
             receiver,
             pipeline: None,
             controls: None,
-            state: CameraState::Idle,
-            configuration: Configuration::default(),
+            state,
+            configuration,
+            state_sender,
+            configuration_sender,
+            events,
+            scopes: Arc::new(scopes),
+            snapshots: Arc::new(snapshots),
+            mjpeg: Arc::new(mjpeg),
+            whep,
+            feeds,
+            whip: None,
+            whip_status: Arc::new(whip_status),
+            srt: None,
+            hls: None,
+            srt_status: Arc::new(srt_status),
+            webrtc,
+            gallery: BTreeSet::new(),
+        }
+    }
+
+    fn set_state(&mut self, state: CameraState) {
+        self.state = state;
+        let modified = self.state_sender.send_if_modified(|current| {
+            let modified = *current != state;
+            *current = state;
+            modified
+        });
+        if modified {
+            let _ = self.events.send(CameraEvent::State(state));
+        }
+        self.update_hud();
+    }
+
+    /// Applies the overlay configuration and the recording tally to the composed livefeed.
+    fn update_hud(&self) {
+        let hud = match &self.controls {
+            Some(Controls::Livefeed { hud, .. } | Controls::Capture { hud, .. }) => hud,
+            None => return,
+        };
+        let overlays = self.configuration.overlays;
+        hud.shader.set_property(
+            "uniforms",
+            overlays.as_uniforms(self.state == CameraState::Capture),
+        );
+        hud.timecode.set_property("silent", !overlays.timecode);
+        hud.info.set_property("silent", !overlays.info);
+        hud.info
+            .set_property("text", self.configuration.info_text());
+    }
+
+    fn set_configuration(&mut self, configuration: Configuration) {
+        self.configuration = configuration;
+        let modified = self.configuration_sender.send_if_modified(|current| {
+            let modified = *current != configuration;
+            *current = configuration;
+            modified
+        });
+        if modified {
+            let _ = self.events.send(CameraEvent::Configuration(configuration));
+        }
+    }
+
+    /// Adds the elements that scale an eye down to `output`, behind a queue so they can
+    /// hang off a tee. Returns the first and the last of them.
+    fn add_scaler(&self, pipeline: &Pipeline, output: OutputMode) -> Result<(Element, Element)> {
+        let queue = ElementFactory::make("queue").build()?;
+        let videorate = ElementFactory::make("videorate")
+            .property("drop-only", true)
+            .property("max-rate", i32::from(output.fps))
+            .build()?;
+        let videoscale = ElementFactory::make("videoscale").build()?;
+        let capsfilter = ElementFactory::make("capsfilter")
+            .property("caps", output.as_caps())
+            .build()?;
+
+        pipeline.add_many([&queue, &videorate, &videoscale, &capsfilter])?;
+        Element::link_many([&queue, &videorate, &videoscale, &capsfilter])?;
+
+        Ok((queue, capsfilter))
+    }
+
+    /// Adds the overlays of the composed livefeed, which takes frames in GL memory and
+    /// hands them out in system memory.
+    fn add_hud(&self, pipeline: &Pipeline) -> Result<Hud> {
+        let shader = ElementFactory::make("glshader")
+            .name("hud")
+            .property("fragment", shaders::HUD)
+            .build()?;
+        let queue = ElementFactory::make("queue").build()?;
+        let gldownload = ElementFactory::make("gldownload")
+            .name("gldownload")
+            .build()?;
+        // The text overlays need the frames in system memory.
+        let timecode = ElementFactory::make("timeoverlay")
+            .name("timecode")
+            .property_from_str("time-mode", "elapsed-running-time")
+            .property_from_str("valignment", "bottom")
+            .property_from_str("halignment", "left")
+            .build()?;
+        let info = ElementFactory::make("textoverlay")
+            .name("info")
+            .property_from_str("valignment", "top")
+            .property_from_str("halignment", "right")
+            .build()?;
+
+        pipeline.add_many([&shader, &queue, &gldownload, &timecode, &info])?;
+        Element::link_many([&shader, &queue, &gldownload, &timecode, &info])?;
+
+        Ok(Hud {
+            shader,
+            timecode,
+            info,
+        })
+    }
+
+    /// Adds the branches that compute the scopes of each eye.
+    fn add_scopes_branches(
+        &self,
+        pipeline: &Pipeline,
+        left_tee: &Element,
+        right_tee: &Element,
+    ) -> Result<()> {
+        let scopes = self.scopes.clone();
+        let left_scopes = scopes::add_branch(pipeline, move |eye| {
+            scopes.send_modify(|scopes| scopes.set_left(eye));
+        })?;
+        left_tee.link(&left_scopes)?;
+        let scopes = self.scopes.clone();
+        let right_scopes = scopes::add_branch(pipeline, move |eye| {
+            scopes.send_modify(|scopes| scopes.set_right(eye));
+        })?;
+        right_tee.link(&right_scopes)?;
+
+        Ok(())
+    }
+
+    /// Adds a branch to the pipeline that keeps the latest frame of `eye` for snapshots.
+    fn add_snapshot_branch(&self, pipeline: &Pipeline, eye: Eye) -> Result<Element> {
+        let snapshots = self.snapshots.clone();
+        snapshot::add_branch(pipeline, move |sample| {
+            snapshots.send_modify(|snapshots| snapshots.set(eye, sample));
+        })
+    }
+
+    fn report_error(&self, error: color_eyre::Report) {
+        warn!("camera error: {error:?}");
+        let _ = self.events.send(CameraEvent::Error(error.to_string()));
+    }
+
+    /// Replaces the WHIP output. Publishing stops when no url is set.
+    fn set_whip(&mut self, settings: WhipSettings) {
+        if let Some(output) = self.whip.take() {
+            output.stop();
+        }
+
+        let Some(url) = settings.url.filter(|url| !url.is_empty()) else {
+            publish_status(
+                &self.whip_status,
+                &self.events,
+                CameraEvent::Whip,
+                |status| {
+                    *status = WhipStatus::default();
+                },
+            );
+            return;
+        };
+
+        let status = self.whip_status.clone();
+        let events = self.events.clone();
+        let status_url = url.clone();
+        let output = WhipOutput::start(
+            &url,
+            settings.token.as_deref(),
+            &self.feeds.composed,
+            move |connection, error| {
+                let update = WhipStatus {
+                    url: Some(status_url.clone()),
+                    connection,
+                    error,
+                };
+                publish_status(&status, &events, CameraEvent::Whip, |status| {
+                    *status = update
+                });
+            },
+        );
+
+        match output {
+            Ok(output) => self.whip = Some(output),
+            Err(err) => {
+                let update = WhipStatus {
+                    url: Some(url),
+                    connection: WhipConnection::Failed,
+                    error: Some(err.to_string()),
+                };
+                publish_status(
+                    &self.whip_status,
+                    &self.events,
+                    CameraEvent::Whip,
+                    |status| {
+                        *status = update;
+                    },
+                );
+                self.report_error(err);
+            }
         }
     }
 
+    /// Restarts the HLS output with the current configuration, or stops it when disabled.
+    async fn restart_hls(&mut self) {
+        if let Some(output) = self.hls.take() {
+            output.stop();
+        }
+        if !self.configuration.hls.enabled {
+            return;
+        }
+
+        let events = self.events.clone();
+        match HlsOutput::start(
+            &self.configuration.hls,
+            &self.feeds.composed,
+            move |error| {
+                let _ = events.send(CameraEvent::Error(error));
+            },
+        )
+        .await
+        {
+            Ok(output) => self.hls = Some(output),
+            Err(err) => self.report_error(err),
+        }
+    }
+
+    /// Replaces the SRT output, or stops it when there are no settings.
+    fn set_srt(&mut self, settings: Option<SrtSettings>) {
+        if let Some(output) = self.srt.take() {
+            output.stop();
+        }
+
+        let Some(settings) = settings else {
+            publish_status(&self.srt_status, &self.events, CameraEvent::Srt, |status| {
+                *status = SrtStatus::default();
+            });
+            return;
+        };
+
+        publish_status(&self.srt_status, &self.events, CameraEvent::Srt, |status| {
+            *status = SrtStatus {
+                address: Some(settings.address.clone()),
+                mode: settings.mode,
+                codec: settings.codec,
+                latency: settings.latency,
+                ..SrtStatus::default()
+            };
+        });
+
+        let status = self.srt_status.clone();
+        let events = self.events.clone();
+        let output = SrtOutput::start(&settings, &self.feeds.composed, move |state, error| {
+            publish_status(&status, &events, CameraEvent::Srt, |status| {
+                status.state = state;
+                status.error = error.clone();
+            });
+        });
+
+        match output {
+            Ok(output) => self.srt = Some(output),
+            Err(err) => {
+                publish_status(&self.srt_status, &self.events, CameraEvent::Srt, |status| {
+                    status.state = SrtState::Failed;
+                    status.error = Some(err.to_string());
+                });
+                self.report_error(err);
+            }
+        }
+    }
+
+    /// Publishes the stats of a running capture and the SRT output,
+    /// and any changes to the gallery.
+    async fn tick(&mut self) {
+        if let Some(srt) = &self.srt {
+            let stats = srt.stats();
+            self.srt_status.send_modify(|status| status.stats = stats);
+        }
+
+        if let Some(Controls::Capture { started, files, .. }) = &self.controls {
+            let mut bytes = 0;
+            for file in files {
+                if let Ok(metadata) = tokio::fs::metadata(gallery::path(file)).await {
+                    bytes += metadata.len();
+                }
+            }
+            let _ = self.events.send(CameraEvent::Recording(RecordingStats {
+                duration: started.elapsed().as_secs_f64(),
+                bytes,
+                files: files.to_vec(),
+            }));
+        }
+
+        let current: BTreeSet<String> = gallery::list().await.into_iter().collect();
+        for added in current.difference(&self.gallery) {
+            let _ = self.events.send(CameraEvent::GalleryAdded(added.clone()));
+        }
+        for removed in self.gallery.difference(&current) {
+            let _ = self
+                .events
+                .send(CameraEvent::GalleryRemoved(removed.clone()));
+        }
+        self.gallery = current;
+    }
+
     /// Provides a graceful shutdown of the current pipeline.
     /// Similar to gst-launch-1.0 with the -e flag.
     async fn clear_pipeline(&mut self) -> Result<()> {
@@ -78,6 +507,9 @@ impl CameraActor {
             shutdown.await?.await;
         }
         self.controls = None;
+        self.scopes.send_replace(Scopes::default());
+        self.snapshots.send_replace(Snapshots::default());
+        self.whep.send_replace(None);
 
         Ok(())
     }
@@ -130,10 +562,8 @@ impl CameraActor {
 
             match self.configuration.codec {
                 VideoCodec::Prores => {
-                    left_enc = ElementFactory::make("avenc_prores")
-                        .build()?;
-                    right_enc = ElementFactory::make("avenc_prores")
-                        .build()?;
+                    left_enc = ElementFactory::make("avenc_prores").build()?;
+                    right_enc = ElementFactory::make("avenc_prores").build()?;
                 }
                 VideoCodec::MotionJpeg => {
                     left_enc = ElementFactory::make("jpegenc")
@@ -176,6 +606,9 @@ impl CameraActor {
             }
         }
 
+        let left_tee = ElementFactory::make("tee").build()?;
+        let right_tee = ElementFactory::make("tee").build()?;
+
         let left_queue = ElementFactory::make("queue").build()?;
         let right_queue = ElementFactory::make("queue").build()?;
 
@@ -201,11 +634,13 @@ impl CameraActor {
         pipeline.add_many([
             &left_src,
             &left_conv,
+            &left_tee,
             &left_queue,
             &left_videoconvert,
             &left_enc,
             &right_src,
             &right_conv,
+            &right_tee,
             &right_queue,
             &right_videoconvert,
             &right_enc,
@@ -216,13 +651,28 @@ impl CameraActor {
         ])?;
 
         left_src.link_filtered(&left_conv, &caps)?;
-        left_conv.link(&left_queue)?;
+        right_src.link_filtered(&right_conv, &caps)?;
+        // The eyes are scaled to the capture output before anything else gets them,
+        // unless they are recorded in the sensor mode.
+        let capture = self.configuration.capture();
+        if capture == self.configuration.sensor_mode() {
+            left_conv.link(&left_tee)?;
+            right_conv.link(&right_tee)?;
+        } else {
+            let (left_scaler, left_capture) = self.add_scaler(&pipeline, capture)?;
+            let (right_scaler, right_capture) = self.add_scaler(&pipeline, capture)?;
+            left_conv.link(&left_scaler)?;
+            left_capture.link(&left_tee)?;
+            right_conv.link(&right_scaler)?;
+            right_capture.link(&right_tee)?;
+        }
+
+        left_tee.link(&left_queue)?;
         left_queue.link(&left_videoconvert)?;
         left_videoconvert.link(&left_enc)?;
         left_enc.link(&left_mux)?;
 
-        right_src.link_filtered(&right_conv, &caps)?;
-        right_conv.link(&right_queue)?;
+        right_tee.link(&right_queue)?;
         right_queue.link(&right_videoconvert)?;
         right_videoconvert.link(&right_enc)?;
         right_enc.link(&right_mux)?;
@@ -230,6 +680,27 @@ impl CameraActor {
         left_mux.link(&left_sink)?;
         right_mux.link(&right_sink)?;
 
+        self.add_scopes_branches(&pipeline, &left_tee, &right_tee)?;
+        left_tee.link(&self.add_snapshot_branch(&pipeline, Eye::Left)?)?;
+        right_tee.link(&self.add_snapshot_branch(&pipeline, Eye::Right)?)?;
+        left_tee.link(&self.feeds.left.add_branch(&pipeline)?)?;
+        right_tee.link(&self.feeds.right.add_branch(&pipeline)?)?;
+        // Nothing is composed while recording, so the outputs of the composed livefeed
+        // get the left eye in the preview output instead of freezing, with the overlays
+        // and the tally.
+        let (composed_scaler, composed_preview) =
+            self.add_scaler(&pipeline, self.configuration.preview())?;
+        let composed_glupload = ElementFactory::make("glupload").build()?;
+        let composed_tee = ElementFactory::make("tee").build()?;
+        pipeline.add_many([&composed_glupload, &composed_tee])?;
+        let hud = self.add_hud(&pipeline)?;
+        left_tee.link(&composed_scaler)?;
+        composed_preview.link(&composed_glupload)?;
+        composed_glupload.link(&hud.shader)?;
+        hud.info.link(&composed_tee)?;
+        composed_tee.link(&self.feeds.composed.add_branch(&pipeline)?)?;
+        composed_tee.link(&self.add_snapshot_branch(&pipeline, Eye::Composed)?)?;
+
         let format = format_description::parse("[year]-[month]-[day] [hour]-[minute]-[second]")?;
         let now = OffsetDateTime::now_utc().format(&format)?;
 
@@ -237,13 +708,19 @@ impl CameraActor {
             VideoCodec::Prores => "mov",
             VideoCodec::MotionJpeg => "mkv",
         };
-        left_sink.set_property("location", format!("gallery/{now} left.{ext}"));
-        right_sink.set_property("location", format!("gallery/{now} right.{ext}"));
+        let files = [format!("{now} left.{ext}"), format!("{now} right.{ext}")];
+        left_sink.set_property("location", gallery::path(&files[0]));
+        right_sink.set_property("location", gallery::path(&files[1]));
 
         pipeline.set_state(State::Playing)?;
 
+        self.controls = Some(Controls::Capture {
+            started: Instant::now(),
+            files,
+            hud,
+        });
         self.pipeline = Some(pipeline);
-        self.state = CameraState::Capture;
+        self.set_state(CameraState::Capture);
 
         info!("capture started");
 
@@ -309,6 +786,9 @@ impl CameraActor {
 
         let mix = ElementFactory::make("glstereomix").name("mix").build()?;
 
+        let left_tee = ElementFactory::make("tee").build()?;
+        let right_tee = ElementFactory::make("tee").build()?;
+
         let left_glupload = ElementFactory::make("glupload").build()?;
         let right_glupload = ElementFactory::make("glupload").build()?;
 
@@ -321,71 +801,160 @@ impl CameraActor {
             .property("translation-y", -self.configuration.convergence.1 / 2f32)
             .build()?;
 
+        let left_assist = ElementFactory::make("glshader")
+            .name("left_assist")
+            .property("fragment", shaders::ASSIST)
+            .property("uniforms", self.configuration.exposure_assist.as_uniforms())
+            .build()?;
+        let right_assist = ElementFactory::make("glshader")
+            .name("right_assist")
+            .property("fragment", shaders::ASSIST)
+            .property("uniforms", self.configuration.exposure_assist.as_uniforms())
+            .build()?;
+
+        let (view_mode, view_flags) = self.configuration.view_output();
         let glviewconvert = ElementFactory::make("glviewconvert")
-            .property(
-                "output-mode-override",
-                self.configuration.multiview_mode.as_gst(),
-            )
+            .property("output-mode-override", view_mode)
+            .property("output-flags-override", view_flags)
             .property_from_str(
                 "downmix-mode",
                 self.configuration.anaglyph_format.as_gst_str(),
             )
             .build()?;
 
-        let queue = ElementFactory::make("queue").name("name").build()?;
-        let gldownload = ElementFactory::make("gldownload")
-            .name("gldownload")
+        let analysis = ElementFactory::make("glshader")
+            .name("analysis")
+            .property(
+                "fragment",
+                self.configuration.analysis_mode.fragment_shader(),
+            )
+            .build()?;
+
+        let composed_tee = ElementFactory::make("tee").build()?;
+        // Outputs that show a single picture are announced as mono, so switching between
+        // them on glviewconvert only changes the frame size downstream.
+        let multiview_caps = ElementFactory::make("capssetter")
+            .name("multiview_caps")
+            .property("caps", self.configuration.multiview_caps())
+            .property("join", true)
+            .property("replace", false)
             .build()?;
         let sink = ElementFactory::make("webrtcsink")
             .name("sink")
             .property_from_str("meta", "meta")
-            .property_from_str("congestion-control", "0")
-            .property_from_str("stun-server", "")
             .build()?;
+        self.webrtc.apply(&sink);
 
         pipeline.add_many([
             &left_src,
             &left_conv,
+            &left_tee,
             &left_glupload,
             &left_transform,
+            &left_assist,
             &right_src,
             &right_conv,
+            &right_tee,
             &right_glupload,
             &right_transform,
+            &right_assist,
             &mix,
-            &queue,
             &glviewconvert,
-            &gldownload,
+            &analysis,
+            &multiview_caps,
+            &composed_tee,
             &sink,
         ])?;
 
         left_src.link_filtered(&left_conv, &caps)?;
 
-        left_conv.link(&left_glupload)?;
+        // Only the composed livefeed is scaled down to the preview output,
+        // the tees hand out the eyes in the sensor mode.
+        let preview = self.configuration.preview();
+        let (left_scaler, left_preview) = self.add_scaler(&pipeline, preview)?;
+        left_conv.link(&left_tee)?;
+        left_tee.link(&left_scaler)?;
+        left_preview.link(&left_glupload)?;
         left_glupload.link(&left_transform)?;
-        left_transform.link(&mix)?;
+        left_transform.link(&left_assist)?;
+        left_assist.link(&mix)?;
 
         right_src.link_filtered(&right_conv, &caps)?;
 
-        right_conv.link(&right_glupload)?;
+        let (right_scaler, right_preview) = self.add_scaler(&pipeline, preview)?;
+        right_conv.link(&right_tee)?;
+        right_tee.link(&right_scaler)?;
+        right_preview.link(&right_glupload)?;
         right_glupload.link(&right_transform)?;
-        right_transform.link(&mix)?;
+        right_transform.link(&right_assist)?;
+        right_assist.link(&mix)?;
+
+        self.add_scopes_branches(&pipeline, &left_tee, &right_tee)?;
+        left_tee.link(&self.add_snapshot_branch(&pipeline, Eye::Left)?)?;
+        right_tee.link(&self.add_snapshot_branch(&pipeline, Eye::Right)?)?;
+        left_tee.link(&self.feeds.left.add_branch(&pipeline)?)?;
+        right_tee.link(&self.feeds.right.add_branch(&pipeline)?)?;
 
         mix.link_filtered(&glviewconvert, &mix_caps)?;
-        glviewconvert.link(&queue)?;
-        queue.link(&gldownload)?;
-        gldownload.link(&sink)?;
+        glviewconvert.link(&analysis)?;
+        let hud = self.add_hud(&pipeline)?;
+        analysis.link(&hud.shader)?;
+        hud.info.link(&multiview_caps)?;
+        multiview_caps.link(&composed_tee)?;
+        composed_tee.link(&sink)?;
+        if self.configuration.webrtc_tracks == WebRtcTracks::SeparateEyes {
+            // The composed track comes first, so viewers that show only one track keep it.
+            if let Some(pad) = sink.static_pad("video_0") {
+                pad.set_property("msid", "composed");
+            }
+            // Both eyes are timestamped by the same clock, so clients can align them
+            // with the sender reports.
+            for (tee, msid) in [(&left_tee, "left"), (&right_tee, "right")] {
+                let queue = ElementFactory::make("queue").build()?;
+                pipeline.add(&queue)?;
+                tee.link(&queue)?;
+                let pad = sink
+                    .request_pad_simple("video_%u")
+                    .ok_or_else(|| color_eyre::eyre::eyre!("webrtcsink has no pad for {msid}"))?;
+                pad.set_property("msid", msid);
+                queue
+                    .static_pad("src")
+                    .ok_or_else(|| color_eyre::eyre::eyre!("queue has no src pad"))?
+                    .link(&pad)?;
+            }
+        }
+        composed_tee.link(&self.add_snapshot_branch(&pipeline, Eye::Composed)?)?;
+
+        let mjpeg = self.mjpeg.clone();
+        let (mjpeg_queue, mjpeg_caps) =
+            mjpeg::add_branch(&pipeline, &self.configuration.mjpeg, move |frame| {
+                mjpeg.send_replace(frame);
+            })?;
+        composed_tee.link(&mjpeg_queue)?;
+        composed_tee.link(&self.feeds.composed.add_branch(&pipeline)?)?;
 
         pipeline.set_state(State::Playing)?;
+        self.whep.send_replace(Some(WhepEndpoint::new(
+            &pipeline,
+            &composed_tee,
+            &self.webrtc,
+        )));
 
         self.controls = Some(Controls::Livefeed {
             left_transform,
             right_transform,
+            left_assist,
+            right_assist,
             glviewconvert,
+            multiview_caps,
+            analysis,
+            hud,
+            mjpeg_caps,
         });
+        self.update_hud();
 
         self.pipeline = Some(pipeline);
-        self.state = CameraState::Livefeed;
+        self.set_state(CameraState::Livefeed);
 
         info!("livefeed started");
 
@@ -396,24 +965,49 @@ impl CameraActor {
         gstreamer::init().unwrap();
 
         match message {
-            CameraActorMessage::GetState(sender) => {
-                let _ = sender.send(self.state);
+            CameraActorMessage::StartCapture(sender) => {
+                let result = self.start_capture().await.map_err(|err| {
+                    let message = err.to_string();
+                    self.report_error(err);
+                    message
+                });
+                let _ = sender.send(result);
             }
-            CameraActorMessage::StartCapture() => {
-                self.start_capture().await.unwrap();
-            }
-            CameraActorMessage::StartLivefeed() => {
+            CameraActorMessage::StartLivefeed(sender) => {
                 if let CameraState::Livefeed = self.state {
+                    let _ = sender.send(Ok(()));
                     return;
                 }
 
-                self.start_livefeed().await.unwrap();
+                let result = self.start_livefeed().await.map_err(|err| {
+                    let message = err.to_string();
+                    self.report_error(err);
+                    message
+                });
+                let _ = sender.send(result);
             }
-            CameraActorMessage::SetConfiguration(configuration) => {
+            CameraActorMessage::SetConfiguration(configuration, senders) => {
+                // The HLS output runs in its own pipeline, whatever the camera is doing.
+                if let Some(hls) = configuration.hls {
+                    if hls != self.configuration.hls {
+                        self.set_configuration(Configuration {
+                            hls,
+                            ..self.configuration
+                        });
+                        self.restart_hls().await;
+                    }
+                }
+
                 if let Some(Controls::Livefeed {
                     left_transform,
                     right_transform,
+                    left_assist,
+                    right_assist,
                     glviewconvert,
+                    multiview_caps,
+                    analysis,
+                    mjpeg_caps,
+                    ..
                 }) = &self.controls
                 {
                     let mut needs_restarting = false;
@@ -430,11 +1024,33 @@ impl CameraActor {
                             right_transform.set_property("translation-y", -y / 2f32);
                         }
                     }
-                    if let Some(multiview_mode) = configuration.multiview_mode {
-                        if multiview_mode != self.configuration.multiview_mode {
-                            needs_restarting = true;
-                            // glviewconvert
-                            // .set_property("output-mode-override", multiview_mode.as_gst());
+                    if let Some(mjpeg) = configuration.mjpeg {
+                        if mjpeg != self.configuration.mjpeg {
+                            mjpeg_caps.set_property("caps", mjpeg.as_caps());
+                        }
+                    }
+                    if let Some(exposure_assist) = configuration.exposure_assist {
+                        if exposure_assist != self.configuration.exposure_assist {
+                            left_assist.set_property("uniforms", exposure_assist.as_uniforms());
+                            right_assist.set_property("uniforms", exposure_assist.as_uniforms());
+                        }
+                    }
+                    if let Some(analysis_mode) = configuration.analysis_mode {
+                        if analysis_mode != self.configuration.analysis_mode {
+                            analysis.set_property("fragment", analysis_mode.fragment_shader());
+                        }
+                    }
+                    let merged = self.configuration.merge(&configuration);
+                    let view_output = merged.view_output();
+                    if view_output != self.configuration.view_output() {
+                        let (view_mode, view_flags) = view_output;
+                        glviewconvert.set_property("output-mode-override", view_mode);
+                        glviewconvert.set_property("output-flags-override", view_flags);
+                        multiview_caps.set_property("caps", merged.multiview_caps());
+                        // The output caps depend on the mode, so have them renegotiated
+                        // with the next buffer.
+                        if let Some(pad) = glviewconvert.static_pad("src") {
+                            pad.mark_reconfigure();
                         }
                     }
                     if let Some(anaglyph_format) = configuration.anaglyph_format {
@@ -465,72 +1081,315 @@ impl CameraActor {
                             needs_restarting = true;
                         }
                     }
-                    self.configuration = self.configuration.merge(&configuration);
+                    if let Some(preview) = configuration.preview {
+                        if preview != self.configuration.preview {
+                            needs_restarting = true;
+                        }
+                    }
+                    // webrtcsink can't add tracks to running sessions.
+                    if let Some(webrtc_tracks) = configuration.webrtc_tracks {
+                        if webrtc_tracks != self.configuration.webrtc_tracks {
+                            needs_restarting = true;
+                        }
+                    }
+                    self.set_configuration(self.configuration.merge(&configuration));
+                    self.update_hud();
 
                     if needs_restarting && self.state == CameraState::Livefeed {
-                        self.start_livefeed().await.unwrap();
+                        if let Err(err) = self.start_livefeed().await {
+                            self.report_error(err);
+                        }
+                    }
+                } else if let Some(Controls::Capture { .. }) = &self.controls {
+                    // The overlays of the preview can still be toggled while recording.
+                    if let Some(overlays) = configuration.overlays {
+                        self.set_configuration(Configuration {
+                            overlays,
+                            ..self.configuration
+                        });
+                        self.update_hud();
                     }
                 }
+
+                for sender in senders {
+                    let _ = sender.send(self.configuration);
+                }
+            }
+            CameraActorMessage::SetWhip(settings, sender) => {
+                self.set_whip(settings);
+                let _ = sender.send(self.whip_status.borrow().clone());
             }
-            CameraActorMessage::GetConfiguration(sender) => {
-                let _ = sender.send(self.configuration);
+            CameraActorMessage::SetSrt(settings, sender) => {
+                self.set_srt(settings);
+                let _ = sender.send(self.srt_status.borrow().clone());
             }
             CameraActorMessage::Shutdown() => {
                 self.receiver.close();
+                if let Some(output) = self.srt.take() {
+                    output.stop();
+                }
+                if let Some(output) = self.hls.take() {
+                    output.stop();
+                }
+                if let Some(output) = self.whip.take() {
+                    output.stop();
+                }
                 self.clear_pipeline().await.unwrap();
-                self.state = CameraState::Idle;
+                self.set_state(CameraState::Idle);
             }
         }
     }
 
     async fn run(mut actor: Self) {
-        while let Some(message) = actor.receiver.recv().await {
+        // Recordings go there, and the gallery is listed every tick.
+        if let Err(err) = tokio::fs::create_dir_all(gallery::GALLERY_DIR).await {
+            warn!("Failed to create the gallery directory: {err}");
+        }
+        actor.gallery = gallery::list().await.into_iter().collect();
+
+        let mut tick = tokio::time::interval(TICK_INTERVAL);
+        tick.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        let mut next = None;
+        loop {
+            let message = match next.take() {
+                Some(message) => message,
+                None => tokio::select! {
+                    message = actor.receiver.recv() => match message {
+                        Some(message) => message,
+                        None => break,
+                    },
+                    _ = tick.tick() => {
+                        actor.tick().await;
+                        continue;
+                    }
+                },
+            };
+
+            // Dragging a slider in the frontend sends a burst of configuration updates.
+            // Applying each of them would only hold up the pipeline with stale values,
+            // so fold everything that is already queued into a single update.
+            let message = match message {
+                CameraActorMessage::SetConfiguration(mut configuration, mut senders) => {
+                    while let Ok(pending) = actor.receiver.try_recv() {
+                        match pending {
+                            CameraActorMessage::SetConfiguration(newer, newer_senders) => {
+                                configuration = configuration.merge(&newer);
+                                senders.extend(newer_senders);
+                            }
+                            other => {
+                                next = Some(other);
+                                break;
+                            }
+                        }
+                    }
+                    CameraActorMessage::SetConfiguration(configuration, senders)
+                }
+                message => message,
+            };
+
             actor.handle_message(message).await;
         }
     }
 }
 
+/// Updates the status of an output and tells the clients when it changed.
+fn publish_status<T: Clone + PartialEq>(
+    sender: &watch::Sender<T>,
+    events: &broadcast::Sender<CameraEvent>,
+    event: fn(T) -> CameraEvent,
+    update: impl FnOnce(&mut T),
+) {
+    let modified = sender.send_if_modified(|status| {
+        let previous = status.clone();
+        update(status);
+        *status != previous
+    });
+    if modified {
+        let _ = events.send(event(sender.borrow().clone()));
+    }
+}
+
 #[derive(Clone)]
 pub struct CameraActorHandle {
     sender: mpsc::Sender<CameraActorMessage>,
+    state: watch::Receiver<CameraState>,
+    configuration: watch::Receiver<Configuration>,
+    events: broadcast::Sender<CameraEvent>,
+    scopes: watch::Receiver<Scopes>,
+    snapshots: watch::Receiver<Snapshots>,
+    mjpeg: watch::Receiver<Bytes>,
+    whep: watch::Receiver<Option<Arc<WhepEndpoint>>>,
+    whip_status: watch::Receiver<WhipStatus>,
+    srt_status: watch::Receiver<SrtStatus>,
+    #[cfg(feature = "rtsp")]
+    feeds: Feeds,
 }
 
 impl CameraActorHandle {
-    pub fn new() -> Self {
+    pub fn new(webrtc: WebRtcSettings) -> Self {
         let (sender, receiver) = mpsc::channel(4);
-        let actor = CameraActor::new(receiver);
+        let (state_sender, state) = watch::channel(CameraState::default());
+        let (configuration_sender, configuration) = watch::channel(Configuration::default());
+        let (events, _) = broadcast::channel(64);
+        let (scopes_sender, scopes) = watch::channel(Scopes::default());
+        let (snapshots_sender, snapshots) = watch::channel(Snapshots::default());
+        let (mjpeg_sender, mjpeg) = watch::channel(Bytes::new());
+        let (whep_sender, whep) = watch::channel(None);
+        let (whip_status_sender, whip_status) = watch::channel(WhipStatus::default());
+        let feeds = Feeds::default();
+        let (srt_status_sender, srt_status) = watch::channel(SrtStatus::default());
+        let actor = CameraActor::new(
+            receiver,
+            state_sender,
+            configuration_sender,
+            events.clone(),
+            scopes_sender,
+            snapshots_sender,
+            mjpeg_sender,
+            whep_sender,
+            whip_status_sender,
+            feeds.clone(),
+            srt_status_sender,
+            webrtc,
+        );
         tokio::spawn(CameraActor::run(actor));
-        Self { sender }
+        Self {
+            sender,
+            state,
+            configuration,
+            events,
+            scopes,
+            snapshots,
+            mjpeg,
+            whep,
+            whip_status,
+            srt_status,
+            #[cfg(feature = "rtsp")]
+            feeds,
+        }
+    }
+
+    /// The latest scopes of both eyes. Empty while no pipeline is running.
+    pub fn get_scopes(&self) -> Scopes {
+        self.scopes.borrow().clone()
     }
 
-    pub async fn get_state(&self) -> CameraState {
-        let (sender, receiver) = tokio::sync::oneshot::channel();
-        let _ = self.sender.send(CameraActorMessage::GetState(sender)).await;
-        receiver.await.unwrap()
+    /// The JPEG frames of the MJPEG preview.
+    /// Empty while the livefeed isn't running.
+    pub fn mjpeg_frames(&self) -> watch::Receiver<Bytes> {
+        self.mjpeg.clone()
     }
 
-    pub async fn get_configuration(&self) -> Configuration {
-        let (sender, receiver) = tokio::sync::oneshot::channel();
+    /// The WHEP endpoint of the running livefeed.
+    pub fn whep(&self) -> Option<Arc<WhepEndpoint>> {
+        self.whep.borrow().clone()
+    }
+
+    pub fn get_whip_status(&self) -> WhipStatus {
+        self.whip_status.borrow().clone()
+    }
+
+    /// Starts, restarts or stops publishing the livefeed to a WHIP ingest server.
+    pub async fn set_whip(&self, settings: WhipSettings) -> WhipStatus {
+        let (sender, receiver) = oneshot::channel();
         let _ = self
             .sender
-            .send(CameraActorMessage::GetConfiguration(sender))
+            .send(CameraActorMessage::SetWhip(settings, sender))
             .await;
-        receiver.await.unwrap()
+        receiver.await.unwrap_or_else(|_| self.get_whip_status())
     }
 
-    pub async fn start_capture(&self) {
-        let _ = self.sender.send(CameraActorMessage::StartCapture()).await;
+    /// The HLS playlist of the livefeed, while the HLS output is enabled.
+    pub async fn hls_playlist(&self) -> Option<String> {
+        if !self.get_configuration().hls.enabled {
+            return None;
+        }
+        tokio::fs::read_to_string(HlsStream::playlist_path())
+            .await
+            .ok()
     }
 
-    pub async fn start_livefeed(&self) {
-        let _ = self.sender.send(CameraActorMessage::StartLivefeed()).await;
+    pub fn get_srt_status(&self) -> SrtStatus {
+        self.srt_status.borrow().clone()
+    }
+
+    /// Starts or restarts sending the livefeed over SRT, or stops it without settings.
+    pub async fn set_srt(&self, settings: Option<SrtSettings>) -> SrtStatus {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self
+            .sender
+            .send(CameraActorMessage::SetSrt(settings, sender))
+            .await;
+        receiver.await.unwrap_or_else(|_| self.get_srt_status())
+    }
+
+    /// Serves the livefeed and both eyes over RTSP, fed from the running pipeline.
+    #[cfg(feature = "rtsp")]
+    pub fn start_rtsp(&self, settings: &RtspSettings) -> Result<RtspServer> {
+        rtsp::start(settings, &self.feeds)
+    }
+
+    /// Encodes the latest frame of `eye`.
+    /// Fails when the running pipeline doesn't provide that eye.
+    pub async fn snapshot(&self, eye: Eye, format: ImageFormat) -> Result<Vec<u8>> {
+        let sample = self
+            .snapshots
+            .borrow()
+            .get(eye)
+            .cloned()
+            .ok_or_else(|| color_eyre::eyre::eyre!("no frame available for {eye:?}"))?;
+
+        tokio::task::spawn_blocking(move || snapshot::encode(&sample, format)).await?
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<CameraEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn get_state(&self) -> CameraState {
+        *self.state.borrow()
+    }
+
+    pub fn get_configuration(&self) -> Configuration {
+        *self.configuration.borrow()
+    }
+
+    /// Waits until the capture pipeline is running, or failed to start.
+    pub async fn start_capture(&self) -> Result<(), String> {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self
+            .sender
+            .send(CameraActorMessage::StartCapture(sender))
+            .await;
+        receiver
+            .await
+            .unwrap_or_else(|_| Err("the camera has shut down".into()))
+    }
+
+    /// Waits until the livefeed pipeline is running, or failed to start.
+    pub async fn start_livefeed(&self) -> Result<(), String> {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self
+            .sender
+            .send(CameraActorMessage::StartLivefeed(sender))
+            .await;
+        receiver
+            .await
+            .unwrap_or_else(|_| Err("the camera has shut down".into()))
     }
 
-    pub async fn set_configuration(&self, configuration: NullableConfiguration) {
+    /// Returns the configuration after the update has been applied.
+    pub async fn set_configuration(&self, configuration: NullableConfiguration) -> Configuration {
+        let (sender, receiver) = oneshot::channel();
         let _ = self
             .sender
-            .send(CameraActorMessage::SetConfiguration(configuration))
+            .send(CameraActorMessage::SetConfiguration(
+                configuration,
+                vec![sender],
+            ))
             .await;
+        receiver.await.unwrap_or_else(|_| self.get_configuration())
     }
 
     pub async fn shutdown(&self) {
@@ -540,6 +1399,6 @@ impl CameraActorHandle {
 
 impl Default for CameraActorHandle {
     fn default() -> Self {
-        Self::new()
+        Self::new(WebRtcSettings::default())
     }
 }
@@ -2,15 +2,28 @@ use std::fmt::Display;
 
 use serde::{Deserialize, Serialize};
 
+use super::shaders;
+
 #[derive(Copy, Clone, PartialEq, Debug, Serialize)]
 pub struct Configuration {
+    // The sensor mode, which both outputs are scaled down from.
     pub width: u16,
     pub height: u16,
     pub fps: u16,
     pub format: PixelFormat,
+    /// What the livefeed is scaled down to.
+    pub preview: PreviewOutput,
+    /// What the recordings are scaled down to.
+    pub capture: CaptureOutput,
     pub convergence: (f32, f32),
     pub multiview_mode: MultiviewMode,
     pub anaglyph_format: AnaglyphFormat,
+    pub analysis_mode: AnalysisMode,
+    pub overlays: Overlays,
+    pub exposure_assist: ExposureAssist,
+    pub mjpeg: MjpegPreview,
+    pub hls: HlsStream,
+    pub webrtc_tracks: WebRtcTracks,
     pub codec: VideoCodec,
 }
 
@@ -20,12 +33,56 @@ pub struct NullableConfiguration {
     pub height: Option<u16>,
     pub fps: Option<u16>,
     pub format: Option<PixelFormat>,
+    pub preview: Option<PreviewOutput>,
+    pub capture: Option<CaptureOutput>,
     pub convergence: Option<(f32, f32)>,
     pub multiview_mode: Option<MultiviewMode>,
     pub anaglyph_format: Option<AnaglyphFormat>,
+    pub analysis_mode: Option<AnalysisMode>,
+    pub overlays: Option<Overlays>,
+    pub exposure_assist: Option<ExposureAssist>,
+    pub mjpeg: Option<MjpegPreview>,
+    pub hls: Option<HlsStream>,
+    pub webrtc_tracks: Option<WebRtcTracks>,
     pub codec: Option<VideoCodec>,
 }
 
+impl NullableConfiguration {
+    /// Sizes and rates of zero can't be negotiated, and a rate of zero stops videorate
+    /// for good, so they are rejected before they reach a pipeline.
+    pub fn validate(&self) -> Result<(), String> {
+        let modes = [
+            ("sensor mode", self.width, self.height, self.fps),
+            (
+                "preview",
+                self.preview.map(|preview| preview.width),
+                self.preview.map(|preview| preview.height),
+                self.preview.map(|preview| preview.fps),
+            ),
+            (
+                "capture",
+                self.capture.and_then(|capture| capture.width),
+                self.capture.and_then(|capture| capture.height),
+                self.capture.and_then(|capture| capture.fps),
+            ),
+            (
+                "MJPEG preview",
+                self.mjpeg.map(|mjpeg| mjpeg.width),
+                self.mjpeg.map(|mjpeg| mjpeg.height),
+                self.mjpeg.map(|mjpeg| mjpeg.fps),
+            ),
+        ];
+        for (name, width, height, fps) in modes {
+            if [width, height, fps].contains(&Some(0)) {
+                return Err(format!(
+                    "the size and rate of the {name} need to be at least 1"
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize, Default)]
 pub enum AnaglyphFormat {
     #[serde(rename = "green-magenta")]
@@ -47,6 +104,232 @@ impl AnaglyphFormat {
     }
 }
 
+/// Livefeed views for aligning the rig, which compare both eyes in a single image.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize, Default)]
+pub enum AnalysisMode {
+    /// Show the eyes as configured by the multiview mode.
+    #[serde(rename = "none")]
+    #[default]
+    None,
+    /// Overlay both eyes at 50% opacity.
+    #[serde(rename = "blend")]
+    Blend,
+    /// Show the absolute difference between the eyes.
+    #[serde(rename = "difference")]
+    Difference,
+    /// Draw the edges of the left eye in red and of the right eye in cyan.
+    #[serde(rename = "edges")]
+    Edges,
+    /// Alternate between the eyes.
+    #[serde(rename = "flicker")]
+    Flicker,
+}
+
+impl AnalysisMode {
+    pub fn fragment_shader(&self) -> &'static str {
+        match self {
+            AnalysisMode::None => shaders::PASSTHROUGH,
+            AnalysisMode::Blend => shaders::BLEND,
+            AnalysisMode::Difference => shaders::DIFFERENCE,
+            AnalysisMode::Edges => shaders::EDGES,
+            AnalysisMode::Flicker => shaders::FLICKER,
+        }
+    }
+}
+
+/// Overlays drawn onto the livefeed. Recordings stay clean.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Overlays {
+    /// A red frame around the picture while recording.
+    pub tally: bool,
+    pub timecode: bool,
+    /// Resolution, frame rate and codec.
+    pub info: bool,
+    /// Rule of thirds.
+    pub grid: bool,
+    /// Action and title safe frames.
+    pub safe_areas: bool,
+    /// Margins at the left and right edges to keep the stereo window intact.
+    pub stereo_window: bool,
+}
+
+impl Default for Overlays {
+    fn default() -> Self {
+        Self {
+            tally: true,
+            timecode: false,
+            info: false,
+            grid: false,
+            safe_areas: false,
+            stereo_window: false,
+        }
+    }
+}
+
+impl Overlays {
+    /// The uniforms of the HUD shader.
+    pub fn as_uniforms(&self, recording: bool) -> gstreamer::Structure {
+        let flag = |enabled: bool| if enabled { 1f32 } else { 0f32 };
+
+        gstreamer::Structure::builder("uniforms")
+            .field("tally", flag(self.tally && recording))
+            .field("grid", flag(self.grid))
+            .field("safe_areas", flag(self.safe_areas))
+            .field("stereo_window", flag(self.stereo_window))
+            .build()
+    }
+}
+
+/// Tools for judging focus and exposure on the livefeed, applied to each eye.
+/// Recordings stay clean.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExposureAssist {
+    pub focus_peaking: bool,
+    pub zebras: bool,
+    /// The IRE level from which on zebras are shown.
+    pub zebra_level: u8,
+    pub false_color: bool,
+}
+
+impl Default for ExposureAssist {
+    fn default() -> Self {
+        Self {
+            focus_peaking: false,
+            zebras: false,
+            zebra_level: 95,
+            false_color: false,
+        }
+    }
+}
+
+impl ExposureAssist {
+    /// The uniforms of the exposure assist shader.
+    pub fn as_uniforms(&self) -> gstreamer::Structure {
+        let flag = |enabled: bool| if enabled { 1f32 } else { 0f32 };
+
+        gstreamer::Structure::builder("uniforms")
+            .field("focus_peaking", flag(self.focus_peaking))
+            .field("zebras", flag(self.zebras))
+            .field("zebra_level", self.zebra_level.min(100) as f32 / 100f32)
+            .field("false_color", flag(self.false_color))
+            .build()
+    }
+}
+
+/// The size and rate of the MJPEG preview for clients without WebRTC.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MjpegPreview {
+    pub width: u16,
+    pub height: u16,
+    pub fps: u16,
+}
+
+impl Default for MjpegPreview {
+    fn default() -> Self {
+        Self {
+            width: 640,
+            height: 360,
+            fps: 10,
+        }
+    }
+}
+
+/// Size and rate of the livefeed, independent of the sensor mode.
+/// The livefeed keeps the aspect ratio of the sensor mode and fits within the size.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreviewOutput {
+    pub width: u16,
+    pub height: u16,
+    pub fps: u16,
+}
+
+impl Default for PreviewOutput {
+    fn default() -> Self {
+        Self {
+            width: 1280,
+            height: 720,
+            fps: 30,
+        }
+    }
+}
+
+/// Size and rate of the recordings, each at most that of the sensor mode.
+/// Recordings keep the sensor mode where unset.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct CaptureOutput {
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub fps: Option<u16>,
+}
+
+/// The size and rate an output is scaled to, in the aspect ratio of the sensor mode.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct OutputMode {
+    pub width: u16,
+    pub height: u16,
+    pub fps: u16,
+}
+
+impl OutputMode {
+    /// The size of the output. The rate is limited separately,
+    /// as an output can't be faster than the sensor.
+    pub fn as_caps(&self) -> gstreamer::Caps {
+        gstreamer::Caps::builder("video/x-raw")
+            .field("width", self.width as i32)
+            .field("height", self.height as i32)
+            .build()
+    }
+}
+
+/// The video tracks webrtcsink sends to every viewer.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize, Default)]
+pub enum WebRtcTracks {
+    /// Only the composed livefeed.
+    #[serde(rename = "composed")]
+    #[default]
+    Composed,
+    /// The composed livefeed, followed by the left and right eye at full resolution
+    /// for headsets that render each eye themselves. The tracks carry the msids
+    /// `composed`, `left` and `right`. Every viewer costs three encoders.
+    #[serde(rename = "separate-eyes")]
+    SeparateEyes,
+}
+
+/// HLS output of the livefeed for large numbers of passive viewers.
+/// It gets the left eye while recording.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HlsStream {
+    pub enabled: bool,
+    /// Target duration of a segment in seconds.
+    pub segment_duration: u16,
+    /// Number of segments in the playlist.
+    pub window: u16,
+    /// Writes CMAF (fragmented MP4) segments instead of MPEG-TS.
+    ///
+    /// Low-latency HLS isn't supported: neither hlssink2 nor hlscmafsink write partial
+    /// segments or preload hints, and the playlist can't be reloaded blocking. Players
+    /// stay a few segments behind, so short segments are what lowers the latency. Viewers
+    /// that need less than that should use WHEP or WebRTC.
+    pub cmaf: bool,
+}
+
+impl Default for HlsStream {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            segment_duration: 2,
+            window: 6,
+            cmaf: false,
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize, Default)]
 pub enum VideoCodec {
     Prores,
@@ -136,9 +419,17 @@ impl Default for Configuration {
             height: 720,
             fps: 60,
             format: PixelFormat::NV12,
+            preview: PreviewOutput::default(),
+            capture: CaptureOutput::default(),
             convergence: (0.0, 0.0),
             multiview_mode: MultiviewMode(gstreamer_video::VideoMultiviewMode::SideBySide),
             anaglyph_format: AnaglyphFormat::default(),
+            analysis_mode: AnalysisMode::default(),
+            overlays: Overlays::default(),
+            exposure_assist: ExposureAssist::default(),
+            mjpeg: MjpegPreview::default(),
+            hls: HlsStream::default(),
+            webrtc_tracks: WebRtcTracks::default(),
             codec: VideoCodec::default(),
         }
     }
@@ -151,9 +442,17 @@ impl From<Configuration> for NullableConfiguration {
             height: Some(config.height),
             fps: Some(config.fps),
             format: Some(config.format),
+            preview: Some(config.preview),
+            capture: Some(config.capture),
             convergence: Some(config.convergence),
             multiview_mode: Some(config.multiview_mode),
             anaglyph_format: Some(config.anaglyph_format),
+            analysis_mode: Some(config.analysis_mode),
+            overlays: Some(config.overlays),
+            exposure_assist: Some(config.exposure_assist),
+            mjpeg: Some(config.mjpeg),
+            hls: Some(config.hls),
+            webrtc_tracks: Some(config.webrtc_tracks),
             codec: Some(config.codec),
         }
     }
@@ -168,9 +467,17 @@ impl From<NullableConfiguration> for Configuration {
             height: config.height.unwrap_or(default.height),
             fps: config.fps.unwrap_or(default.fps),
             format: config.format.unwrap_or(default.format),
+            preview: config.preview.unwrap_or(default.preview),
+            capture: config.capture.unwrap_or(default.capture),
             convergence: config.convergence.unwrap_or(default.convergence),
             multiview_mode: config.multiview_mode.unwrap_or(default.multiview_mode),
             anaglyph_format: config.anaglyph_format.unwrap_or(default.anaglyph_format),
+            analysis_mode: config.analysis_mode.unwrap_or(default.analysis_mode),
+            overlays: config.overlays.unwrap_or(default.overlays),
+            exposure_assist: config.exposure_assist.unwrap_or(default.exposure_assist),
+            mjpeg: config.mjpeg.unwrap_or(default.mjpeg),
+            hls: config.hls.unwrap_or(default.hls),
+            webrtc_tracks: config.webrtc_tracks.unwrap_or(default.webrtc_tracks),
             codec: config.codec.unwrap_or(default.codec),
         }
     }
@@ -183,12 +490,102 @@ impl Configuration {
             height: other.height.unwrap_or(self.height),
             fps: other.fps.unwrap_or(self.fps),
             format: other.format.unwrap_or(self.format),
+            preview: other.preview.unwrap_or(self.preview),
+            capture: other.capture.unwrap_or(self.capture),
             convergence: other.convergence.unwrap_or(self.convergence),
             multiview_mode: other.multiview_mode.unwrap_or(self.multiview_mode),
             anaglyph_format: other.anaglyph_format.unwrap_or(self.anaglyph_format),
+            analysis_mode: other.analysis_mode.unwrap_or(self.analysis_mode),
+            overlays: other.overlays.unwrap_or(self.overlays),
+            exposure_assist: other.exposure_assist.unwrap_or(self.exposure_assist),
+            mjpeg: other.mjpeg.unwrap_or(self.mjpeg),
+            hls: other.hls.unwrap_or(self.hls),
+            webrtc_tracks: other.webrtc_tracks.unwrap_or(self.webrtc_tracks),
             codec: other.codec.unwrap_or(self.codec),
         }
     }
+
+    /// The preview output, never larger or faster than the sensor mode.
+    pub fn preview(&self) -> OutputMode {
+        self.fit(self.preview.width, self.preview.height, self.preview.fps)
+    }
+
+    /// The capture output, never larger or faster than the sensor mode.
+    pub fn capture(&self) -> OutputMode {
+        self.fit(
+            self.capture.width.unwrap_or(self.width),
+            self.capture.height.unwrap_or(self.height),
+            self.capture.fps.unwrap_or(self.fps),
+        )
+    }
+
+    /// The sensor mode itself. Outputs that equal it don't need to be scaled.
+    pub fn sensor_mode(&self) -> OutputMode {
+        OutputMode {
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+        }
+    }
+
+    /// The largest size within `width` and `height` that keeps the aspect ratio of the
+    /// sensor mode, so frames are scaled down without being distorted.
+    fn fit(&self, width: u16, height: u16, fps: u16) -> OutputMode {
+        let scale = (width as f64 / self.width.max(1) as f64)
+            .min(height as f64 / self.height.max(1) as f64)
+            .clamp(0.0, 1.0);
+        // Most encoders and converters want even sizes.
+        let even = |size: u16| ((size as f64 * scale) as u16 & !1).max(2);
+        OutputMode {
+            width: even(self.width),
+            height: even(self.height),
+            fps: fps.clamp(1, self.fps.max(1)),
+        }
+    }
+
+    /// The text of the info overlay.
+    pub fn info_text(&self) -> String {
+        format!(
+            "{}x{} {}fps {:?}",
+            self.width, self.height, self.fps, self.codec
+        )
+    }
+
+    /// The multiview caps announced for the composed livefeed. Outputs that show a single
+    /// picture are announced as mono, so switching between them leaves the caps alone.
+    /// Every other mode keeps the caps glviewconvert gives it.
+    pub fn multiview_caps(&self) -> gstreamer::Caps {
+        use gstreamer_video::VideoMultiviewMode::*;
+
+        let caps = gstreamer::Caps::builder("video/x-raw");
+        let single_picture = self.analysis_mode != AnalysisMode::None
+            || matches!(self.multiview_mode.as_gst(), Mono | Left | Right);
+        if single_picture {
+            caps.field("multiview-mode", "mono").build()
+        } else {
+            caps.build()
+        }
+    }
+
+    /// The mode and flags glviewconvert should output.
+    /// The analysis views need both eyes side by side in a frame of the original size.
+    pub fn view_output(
+        &self,
+    ) -> (
+        gstreamer_video::VideoMultiviewMode,
+        gstreamer_video::VideoMultiviewFlags,
+    ) {
+        match self.analysis_mode {
+            AnalysisMode::None => (
+                self.multiview_mode.as_gst(),
+                gstreamer_video::VideoMultiviewFlags::empty(),
+            ),
+            _ => (
+                gstreamer_video::VideoMultiviewMode::SideBySide,
+                gstreamer_video::VideoMultiviewFlags::HALF_ASPECT,
+            ),
+        }
+    }
 }
 
 impl NullableConfiguration {
@@ -198,9 +595,17 @@ impl NullableConfiguration {
             height: other.height.or(self.height),
             fps: other.fps.or(self.fps),
             format: other.format.or(self.format),
+            preview: other.preview.or(self.preview),
+            capture: other.capture.or(self.capture),
             convergence: other.convergence.or(self.convergence),
             multiview_mode: other.multiview_mode.or(self.multiview_mode),
             anaglyph_format: other.anaglyph_format.or(self.anaglyph_format),
+            analysis_mode: other.analysis_mode.or(self.analysis_mode),
+            overlays: other.overlays.or(self.overlays),
+            exposure_assist: other.exposure_assist.or(self.exposure_assist),
+            mjpeg: other.mjpeg.or(self.mjpeg),
+            hls: other.hls.or(self.hls),
+            webrtc_tracks: other.webrtc_tracks.or(self.webrtc_tracks),
             codec: other.codec.or(self.codec),
         }
     }
@@ -219,3 +624,149 @@ impl Display for PixelFormat {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tally_is_only_lit_while_recording() {
+        gstreamer::init().unwrap();
+        let tally = |overlays: Overlays, recording: bool| {
+            overlays.as_uniforms(recording).get::<f32>("tally").unwrap()
+        };
+
+        assert_eq!(tally(Overlays::default(), true), 1.0);
+        assert_eq!(tally(Overlays::default(), false), 0.0);
+        let off = Overlays {
+            tally: false,
+            ..Overlays::default()
+        };
+        assert_eq!(tally(off, true), 0.0);
+    }
+
+    fn sensor(width: u16, height: u16, fps: u16) -> Configuration {
+        Configuration {
+            width,
+            height,
+            fps,
+            ..Configuration::default()
+        }
+    }
+
+    #[test]
+    fn fits_outputs_into_the_sensor_mode() {
+        let configuration = sensor(1920, 1080, 30);
+        let fit = |width, height, fps| configuration.fit(width, height, fps);
+
+        assert_eq!(
+            fit(1280, 720, 30),
+            OutputMode {
+                width: 1280,
+                height: 720,
+                fps: 30
+            }
+        );
+        // The aspect ratio of the sensor mode is kept within the requested size.
+        assert_eq!(
+            fit(1280, 1280, 25),
+            OutputMode {
+                width: 1280,
+                height: 720,
+                fps: 25
+            }
+        );
+        assert_eq!(
+            fit(640, 100, 30),
+            OutputMode {
+                width: 176,
+                height: 100,
+                fps: 30
+            }
+        );
+        // Outputs are never larger or faster than the sensor mode.
+        assert_eq!(fit(3840, 2160, 60), configuration.sensor_mode());
+        // Sizes are even and never empty, rates at least 1.
+        assert_eq!(
+            fit(641, 361, 30),
+            OutputMode {
+                width: 640,
+                height: 360,
+                fps: 30
+            }
+        );
+        assert_eq!(
+            fit(0, 0, 0),
+            OutputMode {
+                width: 2,
+                height: 2,
+                fps: 1
+            }
+        );
+        assert_eq!(
+            sensor(0, 0, 0).fit(1280, 720, 30),
+            OutputMode {
+                width: 2,
+                height: 2,
+                fps: 1
+            }
+        );
+    }
+
+    #[test]
+    fn captures_in_the_sensor_mode_where_unset() {
+        let configuration = sensor(1920, 1080, 30);
+        assert_eq!(configuration.capture(), configuration.sensor_mode());
+
+        let scaled = Configuration {
+            capture: CaptureOutput {
+                width: Some(1280),
+                height: None,
+                fps: Some(24),
+            },
+            ..configuration
+        };
+        assert_eq!(
+            scaled.capture(),
+            OutputMode {
+                width: 1280,
+                height: 720,
+                fps: 24
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_sizes_and_rates_of_zero() {
+        assert!(NullableConfiguration::default().validate().is_ok());
+        let sensor = NullableConfiguration {
+            width: Some(1920),
+            height: Some(1080),
+            fps: Some(30),
+            ..NullableConfiguration::default()
+        };
+        assert!(sensor.validate().is_ok());
+
+        let zero_fps = NullableConfiguration {
+            fps: Some(0),
+            ..NullableConfiguration::default()
+        };
+        assert!(zero_fps.validate().is_err());
+        let empty_preview = NullableConfiguration {
+            preview: Some(PreviewOutput {
+                width: 0,
+                ..PreviewOutput::default()
+            }),
+            ..NullableConfiguration::default()
+        };
+        assert!(empty_preview.validate().is_err());
+        let still_capture = NullableConfiguration {
+            capture: Some(CaptureOutput {
+                fps: Some(0),
+                ..CaptureOutput::default()
+            }),
+            ..NullableConfiguration::default()
+        };
+        assert!(still_capture.validate().is_err());
+    }
+}
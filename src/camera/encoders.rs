@@ -0,0 +1,15 @@
+// Launch descriptions of the encoders used by the outputs.
+// They take raw frames in system memory, as handed out by the feeds.
+
+/// H.264 that browsers can decode.
+#[cfg(target_os = "linux")]
+pub const H264: &str = "nvvidconv ! video/x-raw(memory:NVMM) ! nvv4l2h264enc insert-sps-pps=true idrinterval=30 maxperf-enable=true ! h264parse";
+/// H.264 that browsers can decode.
+#[cfg(not(target_os = "linux"))]
+pub const H264: &str = "videoconvert ! x264enc tune=zerolatency speed-preset=ultrafast key-int-max=30 ! video/x-h264,profile=constrained-baseline ! h264parse";
+
+#[cfg(target_os = "linux")]
+pub const H265: &str = "nvvidconv ! video/x-raw(memory:NVMM) ! nvv4l2h265enc insert-sps-pps=true idrinterval=30 maxperf-enable=true ! h265parse";
+#[cfg(not(target_os = "linux"))]
+pub const H265: &str =
+    "videoconvert ! x265enc tune=zerolatency speed-preset=ultrafast key-int-max=30 ! h265parse";
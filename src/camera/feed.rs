@@ -0,0 +1,144 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use color_eyre::Result;
+use gstreamer::{prelude::*, BusSyncReply, Element, ElementFactory, MessageView, Pipeline};
+use gstreamer_app::AppSrc;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// How long to wait before restarting an output after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Hands the frames of the livefeed to outputs that run in a pipeline of their own,
+/// so an output that fails or stalls can't take the livefeed down with it.
+/// Outputs stay connected when the livefeed is rebuilt.
+#[derive(Clone, Default)]
+pub struct Feed {
+    sources: Arc<Mutex<Vec<AppSrc>>>,
+}
+
+impl Feed {
+    /// Adds a branch to the livefeed that forwards its frames to the connected sources.
+    /// Returns the element the branch has to be linked to.
+    pub fn add_branch(&self, pipeline: &Pipeline) -> Result<Element> {
+        let queue = ElementFactory::make("queue")
+            .property_from_str("leaky", "downstream")
+            .property("max-size-buffers", 2u32)
+            .build()?;
+        let sources = self.sources.clone();
+        let sink = gstreamer_app::AppSink::builder()
+            .caps(&gstreamer::Caps::new_empty_simple("video/x-raw"))
+            .max_buffers(2)
+            .drop(true)
+            .sync(false)
+            .callbacks(
+                gstreamer_app::AppSinkCallbacks::builder()
+                    .new_sample(move |sink| {
+                        let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+                        let sources = sources.lock().unwrap();
+                        if sources.is_empty() {
+                            return Ok(gstreamer::FlowSuccess::Ok);
+                        }
+                        let (Some(buffer), Some(caps)) = (sample.buffer(), sample.caps()) else {
+                            return Ok(gstreamer::FlowSuccess::Ok);
+                        };
+
+                        // The outputs run on their own clock, so they timestamp the frames themselves.
+                        let mut buffer = buffer.copy();
+                        {
+                            let buffer = buffer.make_mut();
+                            buffer.set_pts(None);
+                            buffer.set_dts(None);
+                        }
+                        let caps = caps.to_owned();
+                        for source in sources.iter() {
+                            if source.caps().as_ref() != Some(&caps) {
+                                source.set_caps(Some(&caps));
+                            }
+                            // A stopped output just misses the frame.
+                            let _ = source.push_buffer(buffer.clone());
+                        }
+
+                        Ok(gstreamer::FlowSuccess::Ok)
+                    })
+                    .build(),
+            )
+            .build();
+
+        pipeline.add_many([&queue, sink.upcast_ref()])?;
+        queue.link(&sink)?;
+
+        Ok(queue)
+    }
+
+    /// Creates a source for the frames of the livefeed. It receives them once connected.
+    pub fn source() -> AppSrc {
+        let source = AppSrc::builder()
+            .is_live(true)
+            .do_timestamp(true)
+            .format(gstreamer::Format::Time)
+            .build();
+        // Drop the oldest frames instead of queueing them up when the output falls behind.
+        source.set_property("max-buffers", 2u64);
+        source.set_property_from_str("leaky-type", "downstream");
+        source
+    }
+
+    pub fn connect(&self, source: &AppSrc) {
+        self.sources.lock().unwrap().push(source.clone());
+    }
+
+    pub fn disconnect(&self, source: &AppSrc) {
+        self.sources.lock().unwrap().retain(|s| s != source);
+    }
+}
+
+/// The feeds of both eyes and of the composed livefeed.
+/// The capture pipeline feeds them too, so the outputs keep running while recording.
+#[derive(Clone, Default)]
+pub struct Feeds {
+    pub left: Feed,
+    pub right: Feed,
+    /// The left eye while recording, as nothing is composed then.
+    pub composed: Feed,
+}
+
+/// Restarts the pipeline of an output whenever it fails, until the returned task is aborted.
+/// Errors on the bus count as failures, anything else can report one through the returned sender.
+pub fn supervise(
+    pipeline: &Pipeline,
+    on_failure: impl Fn(String) + Send + 'static,
+    on_restart: impl Fn() + Send + 'static,
+) -> (mpsc::UnboundedSender<String>, JoinHandle<()>) {
+    let (failures, mut failed) = mpsc::unbounded_channel::<String>();
+
+    let bus_failures = failures.clone();
+    if let Some(bus) = pipeline.bus() {
+        bus.set_sync_handler(move |_, message| {
+            if let MessageView::Error(err) = message.view() {
+                let _ = bus_failures.send(err.error().to_string());
+            }
+            BusSyncReply::Drop
+        });
+    }
+
+    let supervised = pipeline.clone();
+    let supervisor = tokio::spawn(async move {
+        while let Some(error) = failed.recv().await {
+            on_failure(error);
+
+            let pipeline = supervised.clone();
+            let _ = tokio::task::spawn_blocking(move || pipeline.set_state(gstreamer::State::Null))
+                .await;
+            tokio::time::sleep(RETRY_INTERVAL).await;
+
+            // Whatever failed during the teardown is moot now.
+            while failed.try_recv().is_ok() {}
+            on_restart();
+            let _ = supervised.set_state(gstreamer::State::Playing);
+        }
+    });
+
+    (failures, supervisor)
+}
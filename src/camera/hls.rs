@@ -0,0 +1,172 @@
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use color_eyre::Result;
+use gstreamer::{prelude::*, Element, ElementFactory, Pipeline};
+use gstreamer_app::AppSrc;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use super::configuration::HlsStream;
+use super::encoders;
+use super::feed::{supervise, Feed};
+
+/// Where the segments are served, so the playlist can point to them.
+pub const SEGMENTS_ROOT: &str = "/hls";
+const PLAYLIST: &str = "live.m3u8";
+
+/// Segments stay around for this many segments after they left the playlist,
+/// so players that are a little behind can still fetch them.
+const EXTRA_SEGMENTS: u32 = 2;
+
+/// The directory the playlist and segments are written to. It's in the runtime directory,
+/// as nothing in it outlives the stream.
+pub fn hls_dir() -> &'static Path {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir)
+            .join("capture")
+            .join("hls")
+    })
+}
+
+fn output_path(name: &str) -> String {
+    hls_dir().join(name).to_string_lossy().into_owned()
+}
+
+/// Whether the file is one the sinks write, the playlist or a segment.
+fn is_output_file(name: &str) -> bool {
+    if name == PLAYLIST {
+        return true;
+    }
+    let Some((stem, extension)) = name.rsplit_once('.') else {
+        return false;
+    };
+    let number = match extension {
+        "ts" | "m4s" => stem.strip_prefix("segment"),
+        "mp4" => stem.strip_prefix("init"),
+        _ => None,
+    };
+    number.is_some_and(|number| !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Removes the playlist and segments of an earlier run, and nothing else.
+async fn remove_output_files() -> std::io::Result<()> {
+    let mut entries = match tokio::fs::read_dir(hls_dir()).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_str().is_some_and(is_output_file) {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    Ok(())
+}
+
+impl HlsStream {
+    pub fn playlist_path() -> String {
+        output_path(PLAYLIST)
+    }
+}
+
+/// Writes the livefeed as HLS playlist and segments, in a pipeline of its own.
+pub struct HlsOutput {
+    feed: Feed,
+    source: AppSrc,
+    pipeline: Pipeline,
+    supervisor: JoinHandle<()>,
+}
+
+impl HlsOutput {
+    pub async fn start(
+        stream: &HlsStream,
+        feed: &Feed,
+        on_failure: impl Fn(String) + Send + 'static,
+    ) -> Result<Self> {
+        // Segments of an earlier run would only confuse players.
+        tokio::fs::create_dir_all(hls_dir()).await?;
+        remove_output_files().await?;
+
+        let source = Feed::source();
+        let pipeline = Pipeline::with_name("hls");
+        let queue = ElementFactory::make("queue").build()?;
+        let encoder = gstreamer::parse::bin_from_description(encoders::H264, true)?;
+        let target_duration = stream.segment_duration.max(1) as u32;
+        let window = stream.window.max(1) as u32;
+
+        let sink = if stream.cmaf {
+            ElementFactory::make("hlscmafsink")
+                .property("location", output_path("segment%05d.m4s"))
+                .property("init-location", output_path("init%05d.mp4"))
+        } else {
+            ElementFactory::make("hlssink2").property("location", output_path("segment%05d.ts"))
+        }
+        .property("playlist-location", HlsStream::playlist_path())
+        .property("playlist-root", SEGMENTS_ROOT)
+        .property("target-duration", target_duration)
+        .property("playlist-length", window)
+        .property("max-files", window + EXTRA_SEGMENTS)
+        .build()?;
+
+        pipeline.add_many([source.upcast_ref(), &queue, encoder.upcast_ref(), &sink])?;
+        Element::link_many([source.upcast_ref(), &queue, encoder.upcast_ref()])?;
+        // hlssink2 only has request pads.
+        encoder.link_pads(None, &sink, if stream.cmaf { None } else { Some("video") })?;
+
+        let (_, supervisor) = supervise(
+            &pipeline,
+            move |error| {
+                warn!("HLS output failed: {error}");
+                on_failure(error);
+            },
+            || {},
+        );
+
+        if let Err(err) = pipeline.set_state(gstreamer::State::Playing) {
+            supervisor.abort();
+            let _ = pipeline.set_state(gstreamer::State::Null);
+            return Err(err.into());
+        }
+        feed.connect(&source);
+        info!("writing the livefeed as HLS to {}", hls_dir().display());
+
+        Ok(Self {
+            feed: feed.clone(),
+            source,
+            pipeline,
+            supervisor,
+        })
+    }
+
+    pub fn stop(self) {
+        self.supervisor.abort();
+        self.feed.disconnect(&self.source);
+        // Writing to disk is quick, and a restart must not race the old pipeline.
+        let _ = self.pipeline.set_state(gstreamer::State::Null);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_playlist_and_segments_are_output_files() {
+        assert!(is_output_file("live.m3u8"));
+        assert!(is_output_file("segment00042.ts"));
+        assert!(is_output_file("segment00042.m4s"));
+        assert!(is_output_file("init00000.mp4"));
+
+        assert!(!is_output_file("other.m3u8"));
+        assert!(!is_output_file("segment.ts"));
+        assert!(!is_output_file("segment00042.mp4"));
+        assert!(!is_output_file("init00000.m4s"));
+        assert!(!is_output_file("segment0004x.ts"));
+        assert!(!is_output_file("recording.mov"));
+        assert!(!is_output_file("segment"));
+    }
+}
@@ -0,0 +1,84 @@
+use axum::body::Bytes;
+use color_eyre::Result;
+use gstreamer::{prelude::*, Element, ElementFactory, Pipeline};
+
+use super::configuration::MjpegPreview;
+
+impl MjpegPreview {
+    pub fn as_caps(&self) -> gstreamer::Caps {
+        gstreamer::Caps::builder("video/x-raw")
+            .field("width", self.width as i32)
+            .field("height", self.height as i32)
+            .field("framerate", gstreamer::Fraction::new(self.fps as i32, 1))
+            .build()
+    }
+}
+
+/// Adds a branch to the pipeline that encodes a low resolution JPEG stream.
+/// Returns the element the branch has to be linked to
+/// and the capsfilter that sets the size and rate of the stream.
+pub fn add_branch(
+    pipeline: &Pipeline,
+    preview: &MjpegPreview,
+    on_frame: impl Fn(Bytes) + Send + Sync + 'static,
+) -> Result<(Element, Element)> {
+    // Never hold up the livefeed, just drop frames when the branch falls behind.
+    let queue = ElementFactory::make("queue")
+        .property_from_str("leaky", "downstream")
+        .property("max-size-buffers", 1u32)
+        .build()?;
+    let videorate = ElementFactory::make("videorate")
+        .property("drop-only", true)
+        .build()?;
+    // Letterboxes the frame when the aspect ratio doesn't match.
+    let videoscale = ElementFactory::make("videoscale")
+        .property("add-borders", true)
+        .build()?;
+    let videoconvert = ElementFactory::make("videoconvert").build()?;
+    let capsfilter = ElementFactory::make("capsfilter")
+        .property("caps", preview.as_caps())
+        .build()?;
+    let jpegenc = ElementFactory::make("jpegenc")
+        .property("quality", 80)
+        .build()?;
+    let sink = gstreamer_app::AppSink::builder()
+        .max_buffers(1)
+        .drop(true)
+        .sync(false)
+        .callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+                    if let Some(buffer) = sample.buffer() {
+                        let map = buffer
+                            .map_readable()
+                            .map_err(|_| gstreamer::FlowError::Error)?;
+                        on_frame(Bytes::copy_from_slice(map.as_slice()));
+                    }
+                    Ok(gstreamer::FlowSuccess::Ok)
+                })
+                .build(),
+        )
+        .build();
+
+    pipeline.add_many([
+        &queue,
+        &videorate,
+        &videoscale,
+        &videoconvert,
+        &capsfilter,
+        &jpegenc,
+        sink.upcast_ref(),
+    ])?;
+    Element::link_many([
+        &queue,
+        &videorate,
+        &videoscale,
+        &videoconvert,
+        &capsfilter,
+        &jpegenc,
+        sink.upcast_ref(),
+    ])?;
+
+    Ok((queue, capsfilter))
+}
@@ -0,0 +1,95 @@
+use std::net::SocketAddr;
+
+use color_eyre::eyre::{OptionExt, Result};
+use gstreamer::{glib, prelude::*, Bin};
+use gstreamer_app::AppSrc;
+use gstreamer_rtsp_server::{prelude::*, RTSPMediaFactory, RTSPServer};
+use tracing::info;
+
+use super::encoders;
+use super::feed::{Feed, Feeds};
+
+#[derive(Clone, Debug)]
+pub struct RtspSettings {
+    pub address: SocketAddr,
+    /// Mount point of the composed livefeed.
+    pub composed: String,
+    /// Mount point of the left eye.
+    pub left: String,
+    /// Mount point of the right eye.
+    pub right: String,
+}
+
+/// Serves the feeds over RTSP until dropped.
+pub struct RtspServer {
+    main_loop: glib::MainLoop,
+    source: Option<glib::SourceId>,
+}
+
+impl Drop for RtspServer {
+    fn drop(&mut self) {
+        if let Some(source) = self.source.take() {
+            source.remove();
+        }
+        self.main_loop.quit();
+    }
+}
+
+pub fn start(settings: &RtspSettings, feeds: &Feeds) -> Result<RtspServer> {
+    let server = RTSPServer::new();
+    server.set_address(&settings.address.ip().to_string());
+    server.set_service(&settings.address.port().to_string());
+
+    let mounts = server
+        .mount_points()
+        .ok_or_eyre("RTSP server has no mount points")?;
+    for (path, feed) in [
+        (&settings.composed, &feeds.composed),
+        (&settings.left, &feeds.left),
+        (&settings.right, &feeds.right),
+    ] {
+        mounts.add_factory(path, media_factory(feed.clone()));
+    }
+
+    // The server runs on a main loop of its own, next to the tokio runtime.
+    let context = glib::MainContext::new();
+    let source = server.attach(Some(&context))?;
+    let main_loop = glib::MainLoop::new(Some(&context), false);
+    let running = main_loop.clone();
+    std::thread::spawn(move || running.run());
+
+    info!("serving RTSP on rtsp://{}", settings.address);
+
+    Ok(RtspServer {
+        main_loop,
+        source: Some(source),
+    })
+}
+
+/// Encodes a feed once for all clients of a mount point.
+fn media_factory(feed: Feed) -> RTSPMediaFactory {
+    let factory = RTSPMediaFactory::new();
+    factory.set_launch(&format!(
+        "( appsrc name=src is-live=true do-timestamp=true format=time max-buffers=2 leaky-type=downstream ! {} ! rtph264pay name=pay0 pt=96 config-interval=-1 )",
+        encoders::H264
+    ));
+    factory.set_shared(true);
+
+    factory.connect_media_configure(move |_, media| {
+        let Some(source) = media
+            .element()
+            .downcast::<Bin>()
+            .ok()
+            .and_then(|bin| bin.by_name("src"))
+            .and_then(|source| source.downcast::<AppSrc>().ok())
+        else {
+            return;
+        };
+
+        feed.connect(&source);
+        let feed = feed.clone();
+        media.connect_unprepared(move |_| feed.disconnect(&source));
+    });
+
+    factory
+}
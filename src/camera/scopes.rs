@@ -0,0 +1,311 @@
+use color_eyre::Result;
+use gstreamer::{prelude::*, Element, ElementFactory, Pipeline};
+use serde::Serialize;
+
+/// Width and height of the frames the scopes are computed from.
+const SAMPLE_WIDTH: i32 = 160;
+const SAMPLE_HEIGHT: i32 = 90;
+/// Frames per second the scopes are computed at.
+const SAMPLE_RATE: i32 = 2;
+
+const HISTOGRAM_BINS: usize = 64;
+const WAVEFORM_COLUMNS: usize = 64;
+const VECTORSCOPE_BINS: usize = 32;
+
+/// The eyes differ in exposure when their average luma is further apart than this.
+const MISMATCH_THRESHOLD: f32 = 0.05;
+
+#[derive(Clone, PartialEq, Debug, Default, Serialize)]
+pub struct Scopes {
+    pub left: Option<EyeScopes>,
+    pub right: Option<EyeScopes>,
+    pub exposure_mismatch: bool,
+}
+
+impl Scopes {
+    pub fn set_left(&mut self, scopes: EyeScopes) {
+        self.left = Some(scopes);
+        self.update_mismatch();
+    }
+
+    pub fn set_right(&mut self, scopes: EyeScopes) {
+        self.right = Some(scopes);
+        self.update_mismatch();
+    }
+
+    fn update_mismatch(&mut self) {
+        self.exposure_mismatch = match (&self.left, &self.right) {
+            (Some(left), Some(right)) => {
+                (left.average_luma - right.average_luma).abs() > MISMATCH_THRESHOLD
+            }
+            _ => false,
+        };
+    }
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Serialize)]
+pub struct EyeScopes {
+    pub histogram: Histogram,
+    /// Luma distribution per column, from left to right.
+    pub waveform: Vec<WaveformColumn>,
+    pub vectorscope: Vectorscope,
+    pub average_luma: f32,
+    /// Percentage of pixels with at least one channel at its maximum.
+    pub clipped_highlights: f32,
+    /// Percentage of pixels that are (close to) black.
+    pub clipped_shadows: f32,
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Serialize)]
+pub struct Histogram {
+    pub luma: Vec<u32>,
+    pub red: Vec<u32>,
+    pub green: Vec<u32>,
+    pub blue: Vec<u32>,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize)]
+pub struct WaveformColumn {
+    pub min: f32,
+    pub average: f32,
+    pub max: f32,
+}
+
+/// The distribution of the pixels over the chroma plane.
+#[derive(Clone, PartialEq, Debug, Default, Serialize)]
+pub struct Vectorscope {
+    /// Bins along each axis.
+    pub size: usize,
+    /// Pixels per bin, row by row from full to no red difference (Cr),
+    /// each row from no to full blue difference (Cb). Neutral pixels end up in the middle.
+    pub counts: Vec<u32>,
+}
+
+impl EyeScopes {
+    /// Computes the scopes of a packed RGB frame.
+    fn compute(data: &[u8], width: usize, height: usize, stride: usize) -> Self {
+        let bin = |value: u8| value as usize * HISTOGRAM_BINS / 256;
+
+        let mut histogram = Histogram {
+            luma: vec![0; HISTOGRAM_BINS],
+            red: vec![0; HISTOGRAM_BINS],
+            green: vec![0; HISTOGRAM_BINS],
+            blue: vec![0; HISTOGRAM_BINS],
+        };
+        let mut columns = vec![(f32::MAX, 0f32, f32::MIN, 0u32); WAVEFORM_COLUMNS];
+        let mut vectorscope = vec![0u32; VECTORSCOPE_BINS * VECTORSCOPE_BINS];
+        // Chroma differences range from -0.5 to 0.5.
+        let chroma_bin = |difference: f32| {
+            ((difference + 0.5) * VECTORSCOPE_BINS as f32).clamp(0.0, VECTORSCOPE_BINS as f32 - 1.0)
+                as usize
+        };
+        let mut luma_sum = 0f32;
+        let mut highlights = 0u32;
+        let mut shadows = 0u32;
+
+        for y in 0..height {
+            let row = &data[y * stride..y * stride + width * 3];
+            for (x, pixel) in row.chunks_exact(3).enumerate() {
+                let (r, g, b) = (pixel[0], pixel[1], pixel[2]);
+                let luma = (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32) / 255f32;
+
+                let luma_bin = (luma * HISTOGRAM_BINS as f32) as usize;
+                histogram.luma[luma_bin.min(HISTOGRAM_BINS - 1)] += 1;
+                histogram.red[bin(r)] += 1;
+                histogram.green[bin(g)] += 1;
+                histogram.blue[bin(b)] += 1;
+
+                // BT.709, like the luma.
+                let cb = (b as f32 / 255f32 - luma) / 1.8556;
+                let cr = (r as f32 / 255f32 - luma) / 1.5748;
+                let row = chroma_bin(-cr);
+                vectorscope[row * VECTORSCOPE_BINS + chroma_bin(cb)] += 1;
+
+                let column = &mut columns[x * WAVEFORM_COLUMNS / width];
+                column.0 = column.0.min(luma);
+                column.1 += luma;
+                column.2 = column.2.max(luma);
+                column.3 += 1;
+
+                luma_sum += luma;
+                if r == u8::MAX || g == u8::MAX || b == u8::MAX {
+                    highlights += 1;
+                }
+                if luma <= 0.02 {
+                    shadows += 1;
+                }
+            }
+        }
+
+        let pixels = (width * height).max(1) as f32;
+
+        Self {
+            histogram,
+            waveform: columns
+                .into_iter()
+                .map(|(min, sum, max, count)| match count {
+                    0 => WaveformColumn::default(),
+                    count => WaveformColumn {
+                        min,
+                        average: sum / count as f32,
+                        max,
+                    },
+                })
+                .collect(),
+            vectorscope: Vectorscope {
+                size: VECTORSCOPE_BINS,
+                counts: vectorscope,
+            },
+            average_luma: luma_sum / pixels,
+            clipped_highlights: highlights as f32 * 100f32 / pixels,
+            clipped_shadows: shadows as f32 * 100f32 / pixels,
+        }
+    }
+}
+
+/// Adds a branch to the pipeline that computes the scopes of the frames it receives
+/// at a low rate. Returns the element the branch has to be linked to.
+pub fn add_branch(
+    pipeline: &Pipeline,
+    on_scopes: impl Fn(EyeScopes) + Send + Sync + 'static,
+) -> Result<Element> {
+    // Never hold up the pipeline, just drop frames when the branch falls behind.
+    let queue = ElementFactory::make("queue")
+        .property_from_str("leaky", "downstream")
+        .property("max-size-buffers", 1u32)
+        .build()?;
+    let videorate = ElementFactory::make("videorate")
+        .property("drop-only", true)
+        .property("max-rate", SAMPLE_RATE)
+        .build()?;
+    let videoscale = ElementFactory::make("videoscale").build()?;
+    let videoconvert = ElementFactory::make("videoconvert").build()?;
+    let caps = gstreamer::Caps::builder("video/x-raw")
+        .field("format", "RGB")
+        .field("width", SAMPLE_WIDTH)
+        .field("height", SAMPLE_HEIGHT)
+        .build();
+    let sink = gstreamer_app::AppSink::builder()
+        .caps(&caps)
+        .max_buffers(1)
+        .drop(true)
+        .sync(false)
+        .callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+                    let (Some(buffer), Some(caps)) = (sample.buffer(), sample.caps()) else {
+                        return Ok(gstreamer::FlowSuccess::Ok);
+                    };
+                    let info = gstreamer_video::VideoInfo::from_caps(caps)
+                        .map_err(|_| gstreamer::FlowError::NotNegotiated)?;
+                    let frame =
+                        gstreamer_video::VideoFrameRef::from_buffer_ref_readable(buffer, &info)
+                            .map_err(|_| gstreamer::FlowError::Error)?;
+                    let data = frame
+                        .plane_data(0)
+                        .map_err(|_| gstreamer::FlowError::Error)?;
+
+                    on_scopes(EyeScopes::compute(
+                        data,
+                        info.width() as usize,
+                        info.height() as usize,
+                        info.stride()[0] as usize,
+                    ));
+
+                    Ok(gstreamer::FlowSuccess::Ok)
+                })
+                .build(),
+        )
+        .build();
+
+    pipeline.add_many([
+        &queue,
+        &videorate,
+        &videoscale,
+        &videoconvert,
+        sink.upcast_ref(),
+    ])?;
+    Element::link_many([
+        &queue,
+        &videorate,
+        &videoscale,
+        &videoconvert,
+        sink.upcast_ref(),
+    ])?;
+
+    Ok(queue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A frame whose left half is black and whose right half is pure red,
+    /// with padding at the end of every row.
+    fn half_red(width: usize, height: usize, stride: usize) -> Vec<u8> {
+        let mut data = vec![0xAA; stride * height];
+        for y in 0..height {
+            for x in 0..width {
+                let pixel = &mut data[y * stride + x * 3..][..3];
+                pixel.copy_from_slice(if x < width / 2 {
+                    &[0, 0, 0]
+                } else {
+                    &[255, 0, 0]
+                });
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn computes_scopes_of_a_synthetic_frame() {
+        let (width, height, stride) = (128, 4, 128 * 3 + 4);
+        let scopes = EyeScopes::compute(&half_red(width, height, stride), width, height, stride);
+        let pixels = (width * height) as u32;
+
+        assert_eq!(scopes.histogram.luma.iter().sum::<u32>(), pixels);
+        assert_eq!(scopes.histogram.red[0], pixels / 2);
+        assert_eq!(scopes.histogram.red[HISTOGRAM_BINS - 1], pixels / 2);
+        assert_eq!(scopes.histogram.green[0], pixels);
+
+        assert_eq!(scopes.clipped_highlights, 50.0);
+        assert_eq!(scopes.clipped_shadows, 50.0);
+        assert!((scopes.average_luma - 0.2126 / 2.0).abs() < 1e-4);
+
+        assert_eq!(scopes.waveform.len(), WAVEFORM_COLUMNS);
+        assert_eq!(scopes.waveform[0].max, 0.0);
+        let red = scopes.waveform[WAVEFORM_COLUMNS - 1];
+        assert!((red.average - 0.2126).abs() < 1e-4);
+
+        let vectorscope = &scopes.vectorscope;
+        assert_eq!(
+            vectorscope.counts.len(),
+            VECTORSCOPE_BINS * VECTORSCOPE_BINS
+        );
+        assert_eq!(vectorscope.counts.iter().sum::<u32>(), pixels);
+        // Black is neutral, red has the largest red difference and a bit less blue.
+        let middle = VECTORSCOPE_BINS / 2;
+        assert_eq!(
+            vectorscope.counts[middle * VECTORSCOPE_BINS + middle],
+            pixels / 2
+        );
+        let red_row = &vectorscope.counts[..VECTORSCOPE_BINS];
+        assert_eq!(red_row[..middle].iter().sum::<u32>(), pixels / 2);
+    }
+
+    #[test]
+    fn flags_an_exposure_mismatch_between_the_eyes() {
+        let (width, height) = (16, 2);
+        let black = EyeScopes::compute(&vec![0; width * height * 3], width, height, width * 3);
+        let white = EyeScopes::compute(&vec![255; width * height * 3], width, height, width * 3);
+
+        let mut scopes = Scopes::default();
+        scopes.set_left(black.clone());
+        assert!(!scopes.exposure_mismatch);
+        scopes.set_right(white);
+        assert!(scopes.exposure_mismatch);
+        scopes.set_right(black);
+        assert!(!scopes.exposure_mismatch);
+    }
+}
@@ -0,0 +1,22 @@
+//! Fragment shaders for the `glshader` stages of the livefeed.
+//!
+//! Every shader is prefixed with `prelude.glsl`,
+//! which declares the inputs `glshader` provides and some helpers.
+
+macro_rules! fragment_shader {
+    ($file:literal) => {
+        concat!(
+            include_str!("shaders/prelude.glsl"),
+            "\n",
+            include_str!($file)
+        )
+    };
+}
+
+pub const PASSTHROUGH: &str = fragment_shader!("shaders/passthrough.frag");
+pub const BLEND: &str = fragment_shader!("shaders/blend.frag");
+pub const DIFFERENCE: &str = fragment_shader!("shaders/difference.frag");
+pub const EDGES: &str = fragment_shader!("shaders/edges.frag");
+pub const FLICKER: &str = fragment_shader!("shaders/flicker.frag");
+pub const HUD: &str = fragment_shader!("shaders/hud.frag");
+pub const ASSIST: &str = fragment_shader!("shaders/assist.frag");
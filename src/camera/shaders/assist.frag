@@ -0,0 +1,57 @@
+uniform float focus_peaking;
+uniform float zebras;
+uniform float zebra_level;
+uniform float false_color;
+
+float sample_luma(vec2 uv) {
+  return luma(texture2D(tex, uv));
+}
+
+float sharpness(vec2 uv) {
+  vec2 d = vec2(1.0 / width, 1.0 / height);
+  float laplacian = 4.0 * sample_luma(uv) - sample_luma(uv + vec2(d.x, 0.0)) -
+                    sample_luma(uv - vec2(d.x, 0.0)) - sample_luma(uv + vec2(0.0, d.y)) -
+                    sample_luma(uv - vec2(0.0, d.y));
+  return abs(laplacian);
+}
+
+// Roughly follows the false color scale common on cinema monitors.
+vec3 false_colors(float y) {
+  if (y < 0.025) {
+    return vec3(0.5, 0.0, 0.5);
+  } else if (y < 0.1) {
+    return vec3(0.0, 0.0, 1.0);
+  } else if (y >= 0.38 && y < 0.44) {
+    // Middle grey.
+    return vec3(0.0, 0.8, 0.0);
+  } else if (y >= 0.52 && y < 0.56) {
+    // Skin tones.
+    return vec3(1.0, 0.5, 0.7);
+  } else if (y >= 0.93 && y < 0.99) {
+    return vec3(1.0, 1.0, 0.0);
+  } else if (y >= 0.99) {
+    return vec3(1.0, 0.0, 0.0);
+  }
+  return vec3(y);
+}
+
+void main() {
+  vec4 color = texture2D(tex, v_texcoord);
+  float y = luma(color);
+  vec2 px = v_texcoord * vec2(width, height);
+
+  if (false_color > 0.5) {
+    color.rgb = false_colors(y);
+  }
+
+  if (zebras > 0.5 && y >= zebra_level) {
+    float stripe = step(0.5, fract((px.x + px.y + time * 30.0) / 12.0));
+    color.rgb = mix(color.rgb, vec3(0.0), stripe * 0.8);
+  }
+
+  if (focus_peaking > 0.5 && sharpness(v_texcoord) > 0.15) {
+    color.rgb = vec3(0.0, 1.0, 0.0);
+  }
+
+  gl_FragColor = color;
+}
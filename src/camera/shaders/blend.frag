@@ -0,0 +1,3 @@
+void main() {
+  gl_FragColor = mix(left_eye(v_texcoord), right_eye(v_texcoord), 0.5);
+}
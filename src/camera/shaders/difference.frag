@@ -0,0 +1,4 @@
+void main() {
+  vec3 difference = abs(left_eye(v_texcoord).rgb - right_eye(v_texcoord).rgb);
+  gl_FragColor = vec4(difference, 1.0);
+}
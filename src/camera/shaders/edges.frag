@@ -0,0 +1,27 @@
+float sobel(vec2 uv, float offset) {
+  vec2 d = vec2(1.0 / width, 1.0 / height);
+
+  float tl = luma(eye(uv + vec2(-d.x, -d.y), offset));
+  float t = luma(eye(uv + vec2(0.0, -d.y), offset));
+  float tr = luma(eye(uv + vec2(d.x, -d.y), offset));
+  float l = luma(eye(uv + vec2(-d.x, 0.0), offset));
+  float r = luma(eye(uv + vec2(d.x, 0.0), offset));
+  float bl = luma(eye(uv + vec2(-d.x, d.y), offset));
+  float b = luma(eye(uv + vec2(0.0, d.y), offset));
+  float br = luma(eye(uv + vec2(d.x, d.y), offset));
+
+  float gx = tr + 2.0 * r + br - tl - 2.0 * l - bl;
+  float gy = bl + 2.0 * b + br - tl - 2.0 * t - tr;
+  return length(vec2(gx, gy));
+}
+
+// Draws the edges of the left eye in red and those of the right eye in cyan
+// over a dimmed blend of both, so offsets between them stand out.
+void main() {
+  float base = luma(mix(left_eye(v_texcoord), right_eye(v_texcoord), 0.5)) * 0.4;
+  float left = smoothstep(0.2, 0.5, sobel(v_texcoord, 0.0));
+  float right = smoothstep(0.2, 0.5, sobel(v_texcoord, 0.5));
+
+  vec3 color = vec3(base) + left * vec3(1.0, 0.0, 0.0) + right * vec3(0.0, 1.0, 1.0);
+  gl_FragColor = vec4(min(color, vec3(1.0)), 1.0);
+}
@@ -0,0 +1,8 @@
+// Alternates between the eyes four times per second.
+void main() {
+  if (mod(floor(time * 4.0), 2.0) < 1.0) {
+    gl_FragColor = left_eye(v_texcoord);
+  } else {
+    gl_FragColor = right_eye(v_texcoord);
+  }
+}
@@ -0,0 +1,52 @@
+uniform float tally;
+uniform float grid;
+uniform float safe_areas;
+uniform float stereo_window;
+
+// 1.0 within `thickness` pixels of `at`, otherwise 0.0.
+float line(float position, float at, float thickness) {
+  return step(abs(position - at), thickness);
+}
+
+// The outline of a centered rectangle covering `scale` of the frame.
+float frame(vec2 px, float scale, float thickness) {
+  vec2 size = vec2(width, height);
+  vec2 lo = size * (1.0 - scale) * 0.5;
+  vec2 hi = size - lo;
+  if (px.x < lo.x - thickness || px.x > hi.x + thickness ||
+      px.y < lo.y - thickness || px.y > hi.y + thickness) {
+    return 0.0;
+  }
+  return min(line(px.x, lo.x, thickness) + line(px.x, hi.x, thickness) +
+             line(px.y, lo.y, thickness) + line(px.y, hi.y, thickness), 1.0);
+}
+
+void main() {
+  vec4 color = texture2D(tex, v_texcoord);
+  vec2 px = v_texcoord * vec2(width, height);
+
+  float white = 0.0;
+  if (grid > 0.5) {
+    white += line(px.x, width / 3.0, 1.0) + line(px.x, width * 2.0 / 3.0, 1.0) +
+             line(px.y, height / 3.0, 1.0) + line(px.y, height * 2.0 / 3.0, 1.0);
+  }
+  if (safe_areas > 0.5) {
+    // Action safe and title safe.
+    white += frame(px, 0.93, 1.0) + frame(px, 0.9, 1.0);
+  }
+  color.rgb = mix(color.rgb, vec3(1.0), min(white, 1.0) * 0.7);
+
+  if (stereo_window > 0.5) {
+    // Dashed lines marking the margins objects in front of the screen plane
+    // should stay clear of to not break the stereo window.
+    float dashed = step(0.5, fract(px.y / 16.0));
+    float guide = (line(px.x, width * 0.02, 1.0) + line(px.x, width * 0.98, 1.0)) * dashed;
+    color.rgb = mix(color.rgb, vec3(1.0, 0.85, 0.0), min(guide, 1.0));
+  }
+
+  if (tally > 0.5) {
+    color.rgb = mix(color.rgb, vec3(1.0, 0.0, 0.0), frame(px, 1.0, 8.0));
+  }
+
+  gl_FragColor = color;
+}
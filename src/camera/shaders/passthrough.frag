@@ -0,0 +1,3 @@
+void main() {
+  gl_FragColor = texture2D(tex, v_texcoord);
+}
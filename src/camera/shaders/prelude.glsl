@@ -0,0 +1,28 @@
+#version 100
+#ifdef GL_ES
+precision mediump float;
+#endif
+
+varying vec2 v_texcoord;
+uniform sampler2D tex;
+uniform float time;
+uniform float width;
+uniform float height;
+
+// The input carries both eyes squeezed side by side into a single frame.
+// `offset` is 0.0 for the left eye and 0.5 for the right eye.
+vec4 eye(vec2 uv, float offset) {
+  return texture2D(tex, vec2(offset + uv.x * 0.5, uv.y));
+}
+
+vec4 left_eye(vec2 uv) {
+  return eye(uv, 0.0);
+}
+
+vec4 right_eye(vec2 uv) {
+  return eye(uv, 0.5);
+}
+
+float luma(vec4 color) {
+  return dot(color.rgb, vec3(0.2126, 0.7152, 0.0722));
+}
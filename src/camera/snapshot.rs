@@ -0,0 +1,111 @@
+use color_eyre::eyre::{eyre, Result};
+use gstreamer::{prelude::*, Element, ElementFactory, Pipeline, Sample};
+use serde::Deserialize;
+
+/// Snapshots are taken from at most this many frames per second.
+const SAMPLE_RATE: i32 = 5;
+
+#[derive(Copy, Clone, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Eye {
+    Left,
+    Right,
+    /// The livefeed as it is sent to the viewers. The left eye with the overlays while
+    /// recording.
+    #[default]
+    Composed,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+    #[default]
+    Jpeg,
+    Png,
+}
+
+impl ImageFormat {
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Png => "image/png",
+        }
+    }
+}
+
+/// The latest frames of the running pipeline.
+#[derive(Clone, Debug, Default)]
+pub struct Snapshots {
+    pub left: Option<Sample>,
+    pub right: Option<Sample>,
+    pub composed: Option<Sample>,
+}
+
+impl Snapshots {
+    pub fn get(&self, eye: Eye) -> Option<&Sample> {
+        match eye {
+            Eye::Left => self.left.as_ref(),
+            Eye::Right => self.right.as_ref(),
+            Eye::Composed => self.composed.as_ref(),
+        }
+    }
+
+    pub fn set(&mut self, eye: Eye, sample: Sample) {
+        match eye {
+            Eye::Left => self.left = Some(sample),
+            Eye::Right => self.right = Some(sample),
+            Eye::Composed => self.composed = Some(sample),
+        }
+    }
+}
+
+/// Adds a branch to the pipeline that hands out the frames it receives
+/// in system memory. Returns the element the branch has to be linked to.
+pub fn add_branch(
+    pipeline: &Pipeline,
+    on_sample: impl Fn(Sample) + Send + Sync + 'static,
+) -> Result<Element> {
+    // Never hold up the rest of the pipeline, just drop frames when the branch falls behind.
+    let queue = ElementFactory::make("queue")
+        .property_from_str("leaky", "downstream")
+        .property("max-size-buffers", 1u32)
+        .build()?;
+    let videorate = ElementFactory::make("videorate")
+        .property("drop-only", true)
+        .property("max-rate", SAMPLE_RATE)
+        .build()?;
+    let videoconvert = ElementFactory::make("videoconvert").build()?;
+    let sink = gstreamer_app::AppSink::builder()
+        .caps(&gstreamer::Caps::new_empty_simple("video/x-raw"))
+        .max_buffers(1)
+        .drop(true)
+        .sync(false)
+        .callbacks(
+            gstreamer_app::AppSinkCallbacks::builder()
+                .new_sample(move |sink| {
+                    let sample = sink.pull_sample().map_err(|_| gstreamer::FlowError::Eos)?;
+                    on_sample(sample);
+                    Ok(gstreamer::FlowSuccess::Ok)
+                })
+                .build(),
+        )
+        .build();
+
+    pipeline.add_many([&queue, &videorate, &videoconvert, sink.upcast_ref()])?;
+    Element::link_many([&queue, &videorate, &videoconvert, sink.upcast_ref()])?;
+
+    Ok(queue)
+}
+
+/// Encodes a frame as an image. This blocks until the conversion is done.
+pub fn encode(sample: &Sample, format: ImageFormat) -> Result<Vec<u8>> {
+    let caps = gstreamer::Caps::new_empty_simple(format.mime_type());
+    let image =
+        gstreamer_video::convert_sample(sample, &caps, gstreamer::ClockTime::from_seconds(2))?;
+    let buffer = image
+        .buffer()
+        .ok_or_else(|| eyre!("converted sample has no buffer"))?;
+    let map = buffer.map_readable()?;
+
+    Ok(map.as_slice().to_vec())
+}
@@ -0,0 +1,324 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use color_eyre::Result;
+use gstreamer::{glib, prelude::*, Element, ElementFactory, Pipeline};
+use gstreamer_app::AppSrc;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use super::encoders;
+use super::feed::{supervise, Feed};
+
+const DEFAULT_LATENCY: u32 = 125;
+/// srtsink takes the latency as a signed integer.
+pub const MAX_LATENCY: u32 = i32::MAX as u32;
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SrtMode {
+    /// Connects to the receiver.
+    #[default]
+    Caller,
+    /// Waits for the receiver to connect.
+    Listener,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SrtCodec {
+    #[default]
+    H264,
+    H265,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SrtSettings {
+    /// `host:port` to call, or `:port` to listen on.
+    pub address: String,
+    #[serde(default)]
+    pub mode: SrtMode,
+    #[serde(default)]
+    pub codec: SrtCodec,
+    /// Receiver buffer in milliseconds.
+    #[serde(default = "default_latency")]
+    pub latency: u32,
+    /// Encrypts the stream when set. Needs to be 10 to 79 characters long.
+    pub passphrase: Option<String>,
+}
+
+fn default_latency() -> u32 {
+    DEFAULT_LATENCY
+}
+
+impl SrtSettings {
+    /// Catches what srtsink would only fail on once it runs, and keep failing on.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.latency > MAX_LATENCY {
+            return Err(format!(
+                "the SRT latency can be at most {MAX_LATENCY} milliseconds"
+            ));
+        }
+        match &self.passphrase {
+            Some(passphrase) => parse_passphrase(passphrase).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// SRT only accepts passphrases of 10 to 79 characters.
+pub fn parse_passphrase(passphrase: &str) -> Result<String, String> {
+    if (10..=79).contains(&passphrase.len()) {
+        Ok(passphrase.to_string())
+    } else {
+        Err("the SRT passphrase needs to be 10 to 79 characters long".into())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SrtState {
+    #[default]
+    Stopped,
+    /// Listening, but no caller has connected yet.
+    Waiting,
+    /// Calling the receiver, or sending to at least one caller.
+    Running,
+    /// Restarts after a short while.
+    Failed,
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Serialize)]
+pub struct SrtStatus {
+    pub address: Option<String>,
+    pub mode: SrtMode,
+    pub codec: SrtCodec,
+    pub latency: u32,
+    pub state: SrtState,
+    /// Why the output failed last.
+    pub error: Option<String>,
+    /// The statistics srtsink reports, such as the round trip time and the bitrate.
+    pub stats: serde_json::Value,
+}
+
+/// Sends the livefeed as MPEG-TS over SRT, in a pipeline of its own.
+pub struct SrtOutput {
+    feed: Feed,
+    source: AppSrc,
+    pipeline: Pipeline,
+    sink: Element,
+    supervisor: JoinHandle<()>,
+}
+
+impl SrtOutput {
+    pub fn start(
+        settings: &SrtSettings,
+        feed: &Feed,
+        on_state: impl Fn(SrtState, Option<String>) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let source = Feed::source();
+        let pipeline = Pipeline::with_name("srt");
+        let queue = ElementFactory::make("queue").build()?;
+        let encoder = gstreamer::parse::bin_from_description(
+            match settings.codec {
+                SrtCodec::H264 => encoders::H264,
+                SrtCodec::H265 => encoders::H265,
+            },
+            true,
+        )?;
+        let mux = ElementFactory::make("mpegtsmux")
+            .property("alignment", 7)
+            .build()?;
+        let sink = ElementFactory::make("srtsink")
+            .property("uri", format!("srt://{}", settings.address))
+            .property_from_str(
+                "mode",
+                match settings.mode {
+                    SrtMode::Caller => "caller",
+                    SrtMode::Listener => "listener",
+                },
+            )
+            .property("latency", settings.latency as i32)
+            // Don't hold up the pipeline until a receiver is there.
+            .property("wait-for-connection", false)
+            .property("sync", false)
+            .build()?;
+        if let Some(passphrase) = &settings.passphrase {
+            sink.set_property("passphrase", passphrase);
+        }
+        let on_state = Arc::new(on_state);
+
+        // A listener only sends once a caller is there.
+        let started = match settings.mode {
+            SrtMode::Caller => SrtState::Running,
+            SrtMode::Listener => SrtState::Waiting,
+        };
+        let callers = Arc::new(AtomicUsize::new(0));
+        if settings.mode == SrtMode::Listener {
+            let added = callers.clone();
+            let on_added = on_state.clone();
+            sink.connect("caller-added", false, move |_| {
+                added.fetch_add(1, Ordering::SeqCst);
+                on_added(SrtState::Running, None);
+                None
+            });
+            let removed = callers.clone();
+            let on_removed = on_state.clone();
+            sink.connect("caller-removed", false, move |_| {
+                let previous = removed
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                        Some(count.saturating_sub(1))
+                    })
+                    .unwrap_or(0);
+                if previous <= 1 {
+                    on_removed(SrtState::Waiting, None);
+                }
+                None
+            });
+        }
+
+        pipeline.add_many([
+            source.upcast_ref(),
+            &queue,
+            encoder.upcast_ref(),
+            &mux,
+            &sink,
+        ])?;
+        Element::link_many([
+            source.upcast_ref(),
+            &queue,
+            encoder.upcast_ref(),
+            &mux,
+            &sink,
+        ])?;
+
+        let on_failure = on_state.clone();
+        let on_restart = on_state.clone();
+        let (_, supervisor) = supervise(
+            &pipeline,
+            move |error| {
+                warn!("SRT output failed: {error}");
+                on_failure(SrtState::Failed, Some(error));
+            },
+            move || {
+                // Callers are dropped along with the failed connection.
+                callers.store(0, Ordering::SeqCst);
+                on_restart(started, None);
+            },
+        );
+
+        if let Err(err) = pipeline.set_state(gstreamer::State::Playing) {
+            supervisor.abort();
+            let _ = pipeline.set_state(gstreamer::State::Null);
+            return Err(err.into());
+        }
+        feed.connect(&source);
+        on_state(started, None);
+        info!("sending the livefeed over SRT to {}", settings.address);
+
+        Ok(Self {
+            feed: feed.clone(),
+            source,
+            pipeline,
+            sink,
+            supervisor,
+        })
+    }
+
+    pub fn stats(&self) -> serde_json::Value {
+        let stats = self.sink.property::<gstreamer::Structure>("stats");
+        structure_to_json(&stats)
+    }
+
+    pub fn stop(self) {
+        self.supervisor.abort();
+        self.feed.disconnect(&self.source);
+        tokio::task::spawn_blocking(move || {
+            let _ = self.pipeline.set_state(gstreamer::State::Null);
+        });
+    }
+}
+
+fn structure_to_json(structure: &gstreamer::StructureRef) -> serde_json::Value {
+    structure
+        .iter()
+        .map(|(name, value)| (name.to_string(), value_to_json(value)))
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn value_to_json(value: &glib::Value) -> serde_json::Value {
+    if let Ok(value) = value.get::<i32>() {
+        value.into()
+    } else if let Ok(value) = value.get::<u32>() {
+        value.into()
+    } else if let Ok(value) = value.get::<i64>() {
+        value.into()
+    } else if let Ok(value) = value.get::<u64>() {
+        value.into()
+    } else if let Ok(value) = value.get::<f64>() {
+        value.into()
+    } else if let Ok(value) = value.get::<bool>() {
+        value.into()
+    } else if let Ok(value) = value.get::<String>() {
+        value.into()
+    } else if let Ok(value) = value.get::<gstreamer::Structure>() {
+        structure_to_json(&value)
+    } else if let Ok(values) = value.get::<glib::ValueArray>() {
+        // A listener reports the stats of every caller.
+        values.iter().map(value_to_json).collect()
+    } else {
+        serde_json::Value::Null
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> SrtSettings {
+        SrtSettings {
+            address: ":9000".into(),
+            mode: SrtMode::Listener,
+            codec: SrtCodec::H264,
+            latency: DEFAULT_LATENCY,
+            passphrase: None,
+        }
+    }
+
+    #[test]
+    fn accepts_passphrases_of_10_to_79_characters() {
+        assert!(parse_passphrase(&"a".repeat(9)).is_err());
+        assert_eq!(parse_passphrase(&"a".repeat(10)), Ok("a".repeat(10)));
+        assert_eq!(parse_passphrase(&"a".repeat(79)), Ok("a".repeat(79)));
+        assert!(parse_passphrase(&"a".repeat(80)).is_err());
+    }
+
+    #[test]
+    fn validates_the_passphrase_and_the_latency() {
+        assert!(settings().validate().is_ok());
+
+        let short_passphrase = SrtSettings {
+            passphrase: Some("secret".into()),
+            ..settings()
+        };
+        assert!(short_passphrase.validate().is_err());
+        let passphrase = SrtSettings {
+            passphrase: Some("a long enough secret".into()),
+            ..settings()
+        };
+        assert!(passphrase.validate().is_ok());
+
+        let latency = SrtSettings {
+            latency: MAX_LATENCY,
+            ..settings()
+        };
+        assert!(latency.validate().is_ok());
+        let too_late = SrtSettings {
+            latency: MAX_LATENCY + 1,
+            ..settings()
+        };
+        assert!(too_late.validate().is_err());
+    }
+}
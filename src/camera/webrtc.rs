@@ -0,0 +1,263 @@
+use gstreamer::{glib, prelude::*, Element};
+use gstreamer_webrtc::WebRTCDataChannel;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use super::configuration::MultiviewMode;
+
+#[derive(Copy, Clone, PartialEq, Debug, clap::ValueEnum)]
+pub enum WebRtcCodec {
+    Vp8,
+    Vp9,
+    H264,
+}
+
+impl WebRtcCodec {
+    fn caps_name(&self) -> &'static str {
+        match self {
+            WebRtcCodec::Vp8 => "video/x-vp8",
+            WebRtcCodec::Vp9 => "video/x-vp9",
+            WebRtcCodec::H264 => "video/x-h264",
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, clap::ValueEnum)]
+pub enum CongestionControl {
+    /// Keeps the start bitrate.
+    #[default]
+    Disabled,
+    /// A simple sender side heuristic.
+    Homegrown,
+    /// Google Congestion Control.
+    Gcc,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, clap::ValueEnum)]
+pub enum IceTransportPolicy {
+    #[default]
+    All,
+    /// Only connects through the TURN servers.
+    Relay,
+}
+
+/// How webrtcsink encodes and connects.
+/// The defaults suit the hotspot, where viewers connect to the rig directly.
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct WebRtcSettings {
+    /// Video codecs to offer the viewers, in order of preference.
+    #[clap(long = "webrtc-codecs", value_enum, value_delimiter = ',')]
+    pub codecs: Vec<WebRtcCodec>,
+
+    /// Lowest bitrate congestion control goes down to, in bits per second.
+    #[clap(long = "webrtc-min-bitrate")]
+    pub min_bitrate: Option<u32>,
+
+    /// Highest bitrate congestion control goes up to, in bits per second.
+    #[clap(long = "webrtc-max-bitrate")]
+    pub max_bitrate: Option<u32>,
+
+    /// Bitrate every session starts with, in bits per second.
+    #[clap(long = "webrtc-start-bitrate")]
+    pub start_bitrate: Option<u32>,
+
+    #[clap(long = "webrtc-congestion-control", value_enum, default_value_t)]
+    pub congestion_control: CongestionControl,
+
+    /// STUN server of the form stun://host:port.
+    #[clap(long)]
+    pub stun_server: Option<String>,
+
+    /// TURN server of the form turn(s)://username:password@host:port. Can be repeated.
+    #[clap(long = "turn-server")]
+    pub turn_servers: Vec<String>,
+
+    #[clap(long, value_enum, default_value_t)]
+    pub ice_transport_policy: IceTransportPolicy,
+
+    /// Where webrtcsink registers as producer, when not the default of the standalone server.
+    #[clap(skip)]
+    pub signaller_uri: Option<String>,
+
+    /// Sent as bearer token when connecting to the signalling of the web server.
+    #[clap(skip)]
+    pub signaller_token: Option<String>,
+
+    /// Receives the requests viewers send over the control data channel.
+    #[clap(skip)]
+    pub remote_control: Option<mpsc::Sender<RemoteControl>>,
+}
+
+/// What a viewer can ask for over the control data channel.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum RemoteRequest {
+    /// Moves the convergence by this much, for arrow keys and gamepad sticks.
+    NudgeConvergence {
+        x: f32,
+        y: f32,
+    },
+    /// Starts or stops recording. Toggles without `enabled`.
+    Record {
+        enabled: Option<bool>,
+    },
+    MultiviewMode {
+        mode: MultiviewMode,
+    },
+}
+
+/// The envelope gstwebrtc-api's remote controller sends requests in.
+#[derive(Deserialize)]
+struct ControlRequest {
+    id: u64,
+    request: serde_json::Value,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type")]
+enum ControlResponse {
+    ControlResponseMessage { id: u64, error: Option<String> },
+}
+
+/// A request from the control data channel of a consumer, answered with `respond`.
+pub struct RemoteControl {
+    /// The signalling peer id of the consumer.
+    pub peer_id: String,
+    pub request: RemoteRequest,
+    id: u64,
+    channel: WebRTCDataChannel,
+}
+
+impl RemoteControl {
+    pub fn respond(self, result: Result<(), String>) {
+        respond(&self.channel, self.id, result.err());
+    }
+}
+
+fn respond(channel: &WebRTCDataChannel, id: u64, error: Option<String>) {
+    let response = ControlResponse::ControlResponseMessage { id, error };
+    if let Ok(response) = serde_json::to_string(&response) {
+        channel.send_string(Some(&response));
+    }
+}
+
+/// Opens the control data channel gstwebrtc-api expects on every new consumer.
+fn add_control_channel(sink: &Element, requests: mpsc::Sender<RemoteControl>) {
+    // Emitted while the session is still in Ready, before the offer is made.
+    sink.connect("consumer-added", false, move |values| {
+        let peer_id = values[1].get::<String>().ok()?;
+        let webrtcbin = values[2].get::<Element>().ok()?;
+        let channel = webrtcbin.emit_by_name::<WebRTCDataChannel>(
+            "create-data-channel",
+            &[&"control", &None::<gstreamer::Structure>],
+        );
+
+        let requests = requests.clone();
+        channel.connect_on_message_string(move |channel, message| {
+            let Some(Ok(ControlRequest { id, request })) = message.map(serde_json::from_str) else {
+                warn!("Invalid control request: {message:?}");
+                return;
+            };
+            let request = match serde_json::from_value(request) {
+                Ok(request) => request,
+                Err(err) => {
+                    respond(channel, id, Some(format!("unsupported request: {err}")));
+                    return;
+                }
+            };
+
+            let control = RemoteControl {
+                peer_id: peer_id.clone(),
+                request,
+                id,
+                channel: channel.clone(),
+            };
+            if let Err(err) = requests.try_send(control) {
+                let control = err.into_inner();
+                respond(channel, control.id, Some("too many requests".into()));
+            }
+        });
+        None
+    });
+}
+
+impl WebRtcSettings {
+    fn ice_transport_policy_nick(&self) -> &'static str {
+        match self.ice_transport_policy {
+            IceTransportPolicy::All => "all",
+            IceTransportPolicy::Relay => "relay",
+        }
+    }
+
+    /// Configures the ICE servers of a plain webrtcbin, like webrtcsink's.
+    pub fn apply_ice(&self, webrtcbin: &Element) {
+        if let Some(server) = &self.stun_server {
+            webrtcbin.set_property("stun-server", server);
+        }
+        for server in &self.turn_servers {
+            if !webrtcbin.emit_by_name::<bool>("add-turn-server", &[server]) {
+                warn!("webrtcbin refused the TURN server {server}");
+            }
+        }
+        webrtcbin.set_property_from_str("ice-transport-policy", self.ice_transport_policy_nick());
+    }
+
+    /// Configures a webrtcsink, or an element derived from it.
+    pub fn apply(&self, sink: &Element) {
+        if !self.codecs.is_empty() {
+            let mut caps = gstreamer::Caps::new_empty();
+            for codec in &self.codecs {
+                caps.merge(gstreamer::Caps::new_empty_simple(codec.caps_name()));
+            }
+            sink.set_property("video-caps", caps);
+        }
+
+        if let Some(bitrate) = self.min_bitrate {
+            sink.set_property("min-bitrate", bitrate);
+        }
+        if let Some(bitrate) = self.max_bitrate {
+            sink.set_property("max-bitrate", bitrate);
+        }
+        if let Some(bitrate) = self.start_bitrate {
+            sink.set_property("start-bitrate", bitrate);
+        }
+
+        sink.set_property_from_str(
+            "congestion-control",
+            match self.congestion_control {
+                CongestionControl::Disabled => "disabled",
+                CongestionControl::Homegrown => "homegrown",
+                CongestionControl::Gcc => "gcc",
+            },
+        );
+
+        // Without a STUN server only host candidates are gathered,
+        // which is all it takes on the hotspot.
+        sink.set_property("stun-server", self.stun_server.as_deref().unwrap_or(""));
+        if !self.turn_servers.is_empty() {
+            sink.set_property(
+                "turn-servers",
+                gstreamer::Array::new(self.turn_servers.iter().map(|server| server.as_str())),
+            );
+        }
+
+        sink.set_property_from_str("ice-transport-policy", self.ice_transport_policy_nick());
+
+        if let Some(requests) = &self.remote_control {
+            add_control_channel(sink, requests.clone());
+        }
+
+        let signaller = sink.property::<glib::Object>("signaller");
+        if let Some(uri) = &self.signaller_uri {
+            signaller.set_property("uri", uri);
+        }
+        // In a header rather than the uri, which ends up in logs.
+        if let Some(token) = &self.signaller_token {
+            let headers = gstreamer::Structure::builder("headers")
+                .field("Authorization", format!("Bearer {token}"))
+                .build();
+            signaller.set_property("headers", headers);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use color_eyre::eyre::{eyre, OptionExt, Result};
+use gstreamer::{prelude::*, Bin, Element, ElementFactory, GhostPad, PadProbeReturn, PadProbeType};
+use gstreamer::{Pipeline, Promise};
+use gstreamer_sdp::SDPMessage;
+use gstreamer_webrtc::{
+    WebRTCICEGatheringState, WebRTCPeerConnectionState, WebRTCSDPType, WebRTCSessionDescription,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use tokio::sync::oneshot;
+use tracing::{info, warn};
+
+use super::encoders;
+use super::WebRtcSettings;
+
+/// We don't trickle candidates, so the answer is only sent once gathering is done.
+const ICE_GATHERING_TIMEOUT: Duration = Duration::from_secs(5);
+/// Every session has an encoder of its own, so only this many players are served at once.
+const MAX_SESSIONS: usize = 4;
+/// Sessions that haven't been connected for this long are torn down, whether the player
+/// never connected or went away without a DELETE.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Returned by [`WhepEndpoint::create_session`] when all sessions are taken.
+#[derive(Debug)]
+pub struct SessionLimit;
+
+impl fmt::Display for SessionLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "all {MAX_SESSIONS} WHEP sessions are taken")
+    }
+}
+
+impl std::error::Error for SessionLimit {}
+
+/// Serves the composed livefeed to WHEP (WebRTC-HTTP Egress Protocol) players.
+/// Every session gets its own encoder and `webrtcbin`, fed from a tee in the livefeed.
+pub struct WhepEndpoint {
+    pipeline: Pipeline,
+    tee: Element,
+    /// The ICE servers and policy, the same as webrtcsink's.
+    webrtc: WebRtcSettings,
+    sessions: Mutex<HashMap<String, Bin>>,
+}
+
+impl WhepEndpoint {
+    pub fn new(pipeline: &Pipeline, tee: &Element, webrtc: &WebRtcSettings) -> Arc<Self> {
+        Arc::new(Self {
+            pipeline: pipeline.clone(),
+            tee: tee.clone(),
+            webrtc: webrtc.clone(),
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Answers the SDP offer of a player.
+    /// Returns the id of the new session and the answer, or [`SessionLimit`] when all
+    /// sessions are taken.
+    pub async fn create_session(self: &Arc<Self>, offer: &str) -> Result<(String, String)> {
+        if self.sessions.lock().unwrap().len() >= MAX_SESSIONS {
+            return Err(SessionLimit.into());
+        }
+        let offer = SDPMessage::parse_buffer(offer.as_bytes())?;
+        let payload = h264_payload(&offer).ok_or_eyre("the offer doesn't accept H.264 video")?;
+
+        let id: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+
+        let (bin, webrtcbin) = self.add_session_bin(&id, payload)?;
+        let full = {
+            let mut sessions = self.sessions.lock().unwrap();
+            sessions.insert(id.clone(), bin);
+            sessions.len() > MAX_SESSIONS
+        };
+        // Another player was faster.
+        if full {
+            self.end_session(&id);
+            return Err(SessionLimit.into());
+        }
+
+        match negotiate(&webrtcbin, offer).await {
+            Ok(answer) => {
+                info!("started WHEP session {id}");
+                tokio::spawn(end_when_idle(Arc::downgrade(self), id.clone(), webrtcbin));
+                Ok((id, answer))
+            }
+            Err(err) => {
+                self.end_session(&id);
+                Err(err)
+            }
+        }
+    }
+
+    /// Tears down a session. Returns whether it existed.
+    pub fn end_session(&self, id: &str) -> bool {
+        let Some(bin) = self.sessions.lock().unwrap().remove(id) else {
+            return false;
+        };
+        info!("ending WHEP session {id}");
+
+        let Some(src_pad) = bin.static_pad("sink").and_then(|pad| pad.peer()) else {
+            let _ = bin.set_state(gstreamer::State::Null);
+            let _ = self.pipeline.remove(&bin);
+            return true;
+        };
+
+        // Only unlink the branch once no buffer is flowing through the tee pad.
+        let pipeline = self.pipeline.clone();
+        let tee = self.tee.clone();
+        src_pad.add_probe(PadProbeType::IDLE, move |src_pad, _| {
+            if let Some(sink_pad) = bin.static_pad("sink") {
+                let _ = src_pad.unlink(&sink_pad);
+            }
+            tee.release_request_pad(src_pad);
+
+            let bin = bin.clone();
+            let pipeline = pipeline.clone();
+            // Changing the state from a streaming thread would deadlock.
+            std::thread::spawn(move || {
+                let _ = bin.set_state(gstreamer::State::Null);
+                let _ = pipeline.remove(&bin);
+            });
+
+            PadProbeReturn::Remove
+        });
+
+        true
+    }
+
+    fn add_session_bin(self: &Arc<Self>, id: &str, payload: i32) -> Result<(Bin, Element)> {
+        let bin = Bin::with_name(&format!("whep-{id}"));
+
+        // A slow player must not hold up the livefeed or the other players.
+        let queue = ElementFactory::make("queue")
+            .property_from_str("leaky", "downstream")
+            .property("max-size-buffers", 2u32)
+            .build()?;
+        let encoder = encoder()?;
+        let pay = ElementFactory::make("rtph264pay")
+            .property("pt", payload as u32)
+            .property("config-interval", -1i32)
+            .property_from_str("aggregate-mode", "zero-latency")
+            .build()?;
+        let webrtcbin = ElementFactory::make("webrtcbin")
+            .property_from_str("bundle-policy", "max-bundle")
+            .build()?;
+        self.webrtc.apply_ice(&webrtcbin);
+
+        bin.add_many([&queue, &encoder, &pay, &webrtcbin])?;
+        Element::link_many([&queue, &encoder, &pay])?;
+        pay.link(&webrtcbin)?;
+
+        let sink = queue
+            .static_pad("sink")
+            .ok_or_eyre("queue has no sink pad")?;
+        bin.add_pad(&GhostPad::with_target(&sink)?)?;
+
+        // Players that disappear without a DELETE are cleaned up once the connection drops.
+        let endpoint: Weak<Self> = Arc::downgrade(self);
+        let session_id = id.to_string();
+        webrtcbin.connect_notify(Some("connection-state"), move |webrtcbin, _| {
+            let state = webrtcbin.property::<WebRTCPeerConnectionState>("connection-state");
+            if matches!(
+                state,
+                WebRTCPeerConnectionState::Failed | WebRTCPeerConnectionState::Closed
+            ) {
+                if let Some(endpoint) = endpoint.upgrade() {
+                    endpoint.end_session(&session_id);
+                }
+            }
+        });
+
+        self.pipeline.add(&bin)?;
+        let src_pad = self
+            .tee
+            .request_pad_simple("src_%u")
+            .ok_or_eyre("failed to request a tee pad")?;
+        let bin_sink = bin.static_pad("sink").ok_or_eyre("bin has no sink pad")?;
+        src_pad.link(&bin_sink)?;
+        bin.sync_state_with_parent()?;
+
+        Ok((bin, webrtcbin))
+    }
+}
+
+/// Ends the session once it hasn't been connected for `IDLE_TIMEOUT`.
+async fn end_when_idle(endpoint: Weak<WhepEndpoint>, id: String, webrtcbin: Element) {
+    let mut idle = Duration::ZERO;
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(endpoint) = endpoint.upgrade() else {
+            return;
+        };
+        if !endpoint.sessions.lock().unwrap().contains_key(&id) {
+            return;
+        }
+
+        let state = webrtcbin.property::<WebRTCPeerConnectionState>("connection-state");
+        if state == WebRTCPeerConnectionState::Connected {
+            idle = Duration::ZERO;
+        } else {
+            idle += IDLE_CHECK_INTERVAL;
+        }
+        if idle >= IDLE_TIMEOUT {
+            info!("WHEP session {id} isn't connected, ending it");
+            endpoint.end_session(&id);
+            return;
+        }
+    }
+}
+
+fn encoder() -> Result<Element> {
+    let bin = gstreamer::parse::bin_from_description(encoders::H264, true)?;
+    Ok(bin.upcast())
+}
+
+/// The payload type the offer uses for H.264, preferring packetization mode 1.
+fn h264_payload(offer: &SDPMessage) -> Option<i32> {
+    let video = offer
+        .medias()
+        .find(|media| media.media() == Some("video"))?;
+    let attributes: Vec<(&str, Option<&str>)> = video
+        .attributes()
+        .map(|attribute| (attribute.key(), attribute.value()))
+        .collect();
+
+    preferred_h264_payload(&attributes)
+}
+
+/// The H.264 payload type among the attributes of a video media, as key and value.
+fn preferred_h264_payload(attributes: &[(&str, Option<&str>)]) -> Option<i32> {
+    let payloads: Vec<i32> = attributes
+        .iter()
+        .filter(|(key, _)| *key == "rtpmap")
+        .filter_map(|(_, value)| {
+            let (payload, encoding) = value.as_ref()?.split_once(' ')?;
+            encoding
+                .to_ascii_uppercase()
+                .starts_with("H264/")
+                .then(|| payload.parse().ok())
+                .flatten()
+        })
+        .collect();
+
+    let packetization_mode_1 = payloads.iter().copied().find(|payload| {
+        attributes.iter().any(|(key, value)| {
+            *key == "fmtp"
+                && value.is_some_and(|value| {
+                    value.starts_with(&format!("{payload} "))
+                        && value.contains("packetization-mode=1")
+                })
+        })
+    });
+
+    packetization_mode_1.or_else(|| payloads.first().copied())
+}
+
+/// Runs the offer/answer exchange and returns the answer with all candidates.
+async fn negotiate(webrtcbin: &Element, offer: SDPMessage) -> Result<String> {
+    let offer = WebRTCSessionDescription::new(WebRTCSDPType::Offer, offer);
+    let (promise, reply) = awaitable_promise();
+    webrtcbin.emit_by_name::<()>("set-remote-description", &[&offer, &promise]);
+    reply.await?;
+
+    let (promise, reply) = awaitable_promise();
+    webrtcbin.emit_by_name::<()>("create-answer", &[&None::<gstreamer::Structure>, &promise]);
+    let answer = reply
+        .await?
+        .ok_or_eyre("webrtcbin didn't create an answer")?
+        .get::<WebRTCSessionDescription>("answer")?;
+
+    let (gathered_sender, gathered) = oneshot::channel();
+    let gathered_sender = Mutex::new(Some(gathered_sender));
+    let handler = webrtcbin.connect_notify(Some("ice-gathering-state"), move |webrtcbin, _| {
+        let state = webrtcbin.property::<WebRTCICEGatheringState>("ice-gathering-state");
+        if state == WebRTCICEGatheringState::Complete {
+            if let Some(sender) = gathered_sender.lock().unwrap().take() {
+                let _ = sender.send(());
+            }
+        }
+    });
+
+    let (promise, reply) = awaitable_promise();
+    webrtcbin.emit_by_name::<()>("set-local-description", &[&answer, &promise]);
+    reply.await?;
+
+    let complete = webrtcbin.property::<WebRTCICEGatheringState>("ice-gathering-state")
+        == WebRTCICEGatheringState::Complete;
+    if !complete
+        && tokio::time::timeout(ICE_GATHERING_TIMEOUT, gathered)
+            .await
+            .is_err()
+    {
+        warn!("ICE gathering didn't complete, answering with the candidates found so far");
+    }
+    webrtcbin.disconnect(handler);
+
+    let description = webrtcbin
+        .property::<Option<WebRTCSessionDescription>>("local-description")
+        .ok_or_eyre("webrtcbin has no local description")?;
+
+    Ok(description.sdp().as_text()?)
+}
+
+/// A promise that can be awaited.
+fn awaitable_promise() -> (
+    Promise,
+    impl std::future::Future<Output = Result<Option<gstreamer::Structure>>>,
+) {
+    let (sender, receiver) = oneshot::channel();
+    let promise = Promise::with_change_func(move |reply| {
+        let _ = sender.send(
+            reply
+                .map(|reply| reply.map(|structure| structure.to_owned()))
+                .map_err(|err| eyre!("promise failed: {err:?}")),
+        );
+    });
+
+    (promise, async move { receiver.await? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefers_h264_with_packetization_mode_1() {
+        let attributes = [
+            ("rtpmap", Some("96 VP8/90000")),
+            ("rtpmap", Some("102 H264/90000")),
+            (
+                "fmtp",
+                Some("102 profile-level-id=42001f;packetization-mode=0"),
+            ),
+            ("rtpmap", Some("106 h264/90000")),
+            (
+                "fmtp",
+                Some("106 profile-level-id=42e01f;packetization-mode=1"),
+            ),
+            ("rtcp-fb", Some("106 nack")),
+        ];
+        assert_eq!(preferred_h264_payload(&attributes), Some(106));
+    }
+
+    #[test]
+    fn falls_back_to_the_first_h264_payload() {
+        let attributes = [
+            ("rtpmap", Some("96 VP8/90000")),
+            ("rtpmap", Some("102 H264/90000")),
+            ("rtpmap", Some("104 H264/90000")),
+            ("sendrecv", None),
+        ];
+        assert_eq!(preferred_h264_payload(&attributes), Some(102));
+    }
+
+    #[test]
+    fn finds_no_payload_without_h264() {
+        let attributes = [
+            ("rtpmap", Some("96 VP8/90000")),
+            ("rtpmap", Some("98 VP9/90000")),
+        ];
+        assert_eq!(preferred_h264_payload(&attributes), None);
+    }
+}
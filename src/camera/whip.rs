@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use color_eyre::Result;
+use gstreamer::{glib, prelude::*, Element, ElementFactory, Pipeline};
+use gstreamer_app::AppSrc;
+use gstreamer_webrtc::WebRTCPeerConnectionState;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use super::feed::{supervise, Feed};
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct WhipSettings {
+    /// The WHIP endpoint to publish to. Publishing stops when this is empty.
+    pub url: Option<String>,
+    /// Sent as bearer token to the endpoint.
+    pub token: Option<String>,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WhipConnection {
+    #[default]
+    Disabled,
+    Connecting,
+    Connected,
+    /// Reconnects after a short while.
+    Failed,
+}
+
+#[derive(Clone, PartialEq, Debug, Default, Serialize)]
+pub struct WhipStatus {
+    pub url: Option<String>,
+    pub connection: WhipConnection,
+    /// Why the last connection attempt failed.
+    pub error: Option<String>,
+}
+
+/// Publishes the livefeed to a WHIP ingest server, in a pipeline of its own.
+pub struct WhipOutput {
+    feed: Feed,
+    source: AppSrc,
+    pipeline: Pipeline,
+    supervisor: JoinHandle<()>,
+}
+
+impl WhipOutput {
+    pub fn start(
+        url: &str,
+        token: Option<&str>,
+        feed: &Feed,
+        on_connection: impl Fn(WhipConnection, Option<String>) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let source = Feed::source();
+        let pipeline = Pipeline::with_name("whip");
+        let queue = ElementFactory::make("queue").build()?;
+        let sink = ElementFactory::make("whipclientsink").build()?;
+
+        let signaller = sink.property::<glib::Object>("signaller");
+        signaller.set_property("whip-endpoint", url);
+        if let Some(token) = token {
+            signaller.set_property("auth-token", token);
+        }
+
+        pipeline.add_many([source.upcast_ref(), &queue, &sink])?;
+        Element::link_many([source.upcast_ref(), &queue, &sink])?;
+
+        let on_connection = Arc::new(on_connection);
+        let on_failure = on_connection.clone();
+        let on_restart = on_connection.clone();
+        let (failures, supervisor) = supervise(
+            &pipeline,
+            move |error| {
+                warn!("WHIP output failed: {error}");
+                on_failure(WhipConnection::Failed, Some(error));
+            },
+            move || on_restart(WhipConnection::Connecting, None),
+        );
+
+        let signaller_failures = failures.clone();
+        signaller.connect("error", false, move |values| {
+            let error = values[1].get::<String>().unwrap_or_default();
+            let _ = signaller_failures.send(error);
+            None
+        });
+
+        let on_peer_connection = on_connection.clone();
+        sink.connect("consumer-added", false, move |values| {
+            let Ok(webrtcbin) = values[2].get::<Element>() else {
+                return None;
+            };
+            let on_connection = on_peer_connection.clone();
+            let failures = failures.clone();
+            webrtcbin.connect_notify(Some("connection-state"), move |webrtcbin, _| {
+                match webrtcbin.property::<WebRTCPeerConnectionState>("connection-state") {
+                    WebRTCPeerConnectionState::Connected => {
+                        on_connection(WhipConnection::Connected, None)
+                    }
+                    WebRTCPeerConnectionState::Failed => {
+                        let _ = failures.send("the connection to the ingest server failed".into());
+                    }
+                    _ => {}
+                }
+            });
+            None
+        });
+
+        on_connection(WhipConnection::Connecting, None);
+        if let Err(err) = pipeline.set_state(gstreamer::State::Playing) {
+            supervisor.abort();
+            let _ = pipeline.set_state(gstreamer::State::Null);
+            return Err(err.into());
+        }
+        feed.connect(&source);
+        info!("publishing the livefeed to {url}");
+
+        Ok(Self {
+            feed: feed.clone(),
+            source,
+            pipeline,
+            supervisor,
+        })
+    }
+
+    pub fn stop(self) {
+        self.supervisor.abort();
+        self.feed.disconnect(&self.source);
+        // Ending the session sends a DELETE to the ingest server, so don't wait for it.
+        tokio::task::spawn_blocking(move || {
+            let _ = self.pipeline.set_state(gstreamer::State::Null);
+        });
+    }
+}
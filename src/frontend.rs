@@ -1,20 +1,79 @@
+use std::convert::Infallible;
 use std::net::SocketAddr;
 
 use axum::{
-    extract::{self},
-    routing::{get, post},
+    body::{Body, Bytes},
+    extract::{self, State},
+    http::{header, uri::Authority, HeaderMap, HeaderValue, StatusCode, Uri},
+    middleware,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Redirect,
+    },
+    routing::{delete, get, post},
+    serve::ListenerExt,
     Json, Router,
 };
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc;
-use tower_http::{cors::CorsLayer, services::ServeDir};
+use tokio_stream::{
+    wrappers::{BroadcastStream, WatchStream},
+    StreamExt,
+};
+use tower_http::services::ServeDir;
 use tracing::warn;
 
-use crate::camera::{CameraActorHandle, NullableConfiguration};
+use crate::audit::{self, AuditLog};
+use crate::auth::{self, Auth, Control};
+use crate::camera::{
+    hls_dir, CameraActorHandle, CameraEvent, CameraState, Eye, ImageFormat, NullableConfiguration,
+    SessionLimit, SrtSettings, SrtStatus, WhipSettings, WhipStatus,
+};
+use crate::gallery;
+use crate::tls::{Acceptor, TlsListener};
+
+const MJPEG_BOUNDARY: &str = "frame";
+
+/// The state of the API routes.
+type Camera = State<CameraActorHandle>;
+
+#[derive(Serialize)]
+struct Status {
+    state: CameraState,
+    whip: WhipStatus,
+    srt: SrtStatus,
+    /// Only there with authentication, everyone is an operator without it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    control: Option<Control>,
+}
+
+#[derive(Deserialize)]
+struct SnapshotQuery {
+    #[serde(default)]
+    eye: Eye,
+    #[serde(default)]
+    format: ImageFormat,
+}
 
 struct WebServerActor {
     address: SocketAddr,
     receiver: mpsc::Receiver<WebServerActorMessage>,
     camera: CameraActorHandle,
+    /// Routes of the optional features, such as signalling.
+    routes: Router,
+    /// Routes that don't need authentication.
+    public_routes: Router,
+    /// Serves HTTPS instead of HTTP when set.
+    tls: Option<Acceptor>,
+    /// Where plain HTTP requests are redirected to HTTPS from.
+    redirect: Option<SocketAddr>,
+    /// Serves the signalling over plain HTTP on loopback next to HTTPS, for webrtcsink.
+    producer: Option<tokio::net::TcpListener>,
+    /// Requests need a token or session when set.
+    auth: Option<Auth>,
+    /// Origins that may call the API from other sites. Any may when empty.
+    cors_origins: Vec<HeaderValue>,
+    audit: AuditLog,
 }
 
 enum WebServerActorMessage {
@@ -32,73 +91,285 @@ impl WebServerActor {
     }
 
     async fn run(mut actor: Self) {
-        let camera = actor.camera.clone();
-        let camera2 = actor.camera.clone();
-        let camera3 = actor.camera.clone();
-        let camera4 = actor.camera.clone();
+        let auth2 = actor.auth.clone();
 
         let app = Router::new()
-            .nest_service("/gallery", ServeDir::new("gallery"))
+            .nest_service("/gallery", ServeDir::new(gallery::GALLERY_DIR))
+            .nest_service("/hls", ServeDir::new(hls_dir()))
             .route(
-                "/api/gallery",
-                get(|| async {
-                    let dir = tokio::fs::read_dir("gallery").await;
-
-                    if dir.is_err() {
-                        warn!("failed to read gallery directory");
-                        return Json(vec![]);
+                "/api/hls/live.m3u8",
+                get(|State(camera): Camera| async move {
+                    match camera.hls_playlist().await {
+                        Some(playlist) => (
+                            [
+                                (header::CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+                                // Players have to see every update of the playlist.
+                                (header::CACHE_CONTROL, "no-cache"),
+                            ],
+                            playlist,
+                        )
+                            .into_response(),
+                        None => StatusCode::NOT_FOUND.into_response(),
                     }
+                }),
+            )
+            .route(
+                "/api/gallery",
+                get(|| async { Json(gallery::list().await) }),
+            )
+            .route(
+                "/api/events",
+                get(|State(camera): Camera| async move {
+                    // Lagging subscribers just miss the events they fell behind on.
+                    let events =
+                        BroadcastStream::new(camera.subscribe()).filter_map(|event| event.ok());
+                    // Start every stream with the current state,
+                    // so clients don't have to fetch it separately.
+                    let initial = tokio_stream::iter([
+                        CameraEvent::State(camera.get_state()),
+                        CameraEvent::Configuration(camera.get_configuration()),
+                    ]);
+                    let stream = initial.chain(events).map(|event| {
+                        Ok::<_, Infallible>(Event::default().json_data(event).unwrap_or_default())
+                    });
 
-                    let mut dir = dir.unwrap();
-                    let mut result = vec![];
+                    Sse::new(stream).keep_alive(KeepAlive::default())
+                }),
+            )
+            .route(
+                "/api/scopes",
+                get(|State(camera): Camera| async move { Json(camera.get_scopes()) }),
+            )
+            .route(
+                "/api/snapshot",
+                get(
+                    |State(camera): Camera,
+                     extract::Query(query): extract::Query<SnapshotQuery>| async move {
+                        match camera.snapshot(query.eye, query.format).await {
+                            Ok(image) => {
+                                ([(header::CONTENT_TYPE, query.format.mime_type())], image)
+                                    .into_response()
+                            }
+                            Err(err) => {
+                                (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response()
+                            }
+                        }
+                    },
+                ),
+            )
+            .route(
+                "/api/mjpeg",
+                get(|State(camera): Camera| async move {
+                    // Every part replaces the previous image, so an `<img>` shows a video.
+                    let frames = WatchStream::new(camera.mjpeg_frames())
+                        .filter(|frame| !frame.is_empty())
+                        .map(|frame| {
+                            let header = format!(
+                                "--{MJPEG_BOUNDARY}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                                frame.len()
+                            );
+                            Ok::<_, Infallible>(Bytes::from(
+                                [header.as_bytes(), &frame, b"\r\n"].concat(),
+                            ))
+                        });
 
-                    while let Ok(Some(entry)) = dir.next_entry().await {
-                        let name = entry.file_name().to_string_lossy().to_string();
-                        if name.ends_with(".mkv")
-                            || name.ends_with(".mov")
-                            || name.ends_with(".mp4")
-                        {
-                            result.push(name);
+                    (
+                        [(
+                            header::CONTENT_TYPE,
+                            format!("multipart/x-mixed-replace; boundary={MJPEG_BOUNDARY}"),
+                        )],
+                        Body::from_stream(frames),
+                    )
+                }),
+            )
+            .route(
+                "/api/whep",
+                post(
+                    |State(camera): Camera, headers: HeaderMap, offer: String| async move {
+                        let sdp = headers
+                            .get(header::CONTENT_TYPE)
+                            .and_then(|value| value.to_str().ok())
+                            .is_some_and(|value| value.starts_with("application/sdp"));
+                        if !sdp {
+                            return (
+                                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                                "the offer must be application/sdp",
+                            )
+                                .into_response();
+                        }
+                        let Some(whep) = camera.whep() else {
+                            return (StatusCode::SERVICE_UNAVAILABLE, "the livefeed isn't running")
+                                .into_response();
+                        };
+                        match whep.create_session(&offer).await {
+                            Ok((id, answer)) => (
+                                StatusCode::CREATED,
+                                [
+                                    (header::CONTENT_TYPE, "application/sdp".to_string()),
+                                    (header::LOCATION, format!("/api/whep/{id}")),
+                                ],
+                                answer,
+                            )
+                                .into_response(),
+                            Err(err) if err.is::<SessionLimit>() => {
+                                (StatusCode::SERVICE_UNAVAILABLE, err.to_string()).into_response()
+                            }
+                            Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
                         }
+                    },
+                ),
+            )
+            .route(
+                "/api/whep/{id}",
+                delete(
+                    |State(camera): Camera, extract::Path(id): extract::Path<String>| async move {
+                    match camera.whep() {
+                        Some(whep) if whep.end_session(&id) => StatusCode::OK,
+                        _ => StatusCode::NOT_FOUND,
                     }
-
-                    Json(result)
                 }),
             )
+            .route(
+                "/api/whip",
+                get(|State(camera): Camera| async move { Json(camera.get_whip_status()) }).post(
+                    |State(camera): Camera,
+                     extract::Json(payload): extract::Json<WhipSettings>| async move {
+                        Json(camera.set_whip(payload).await)
+                    },
+                ),
+            )
+            .route(
+                "/api/srt",
+                get(|State(camera): Camera| async move { Json(camera.get_srt_status()) })
+                    .post(
+                        |State(camera): Camera,
+                         extract::Json(payload): extract::Json<SrtSettings>| async move {
+                            if let Err(err) = payload.validate() {
+                                return (StatusCode::BAD_REQUEST, err).into_response();
+                            }
+                            Json(camera.set_srt(Some(payload)).await).into_response()
+                        },
+                    )
+                    .delete(|State(camera): Camera| async move {
+                        Json(camera.set_srt(None).await)
+                    }),
+            )
             .route(
                 "/api/state",
-                get(|| async move { Json(camera2.get_state().await) }),
+                get(|State(camera): Camera, headers: HeaderMap, uri: Uri| async move {
+                    Json(Status {
+                        state: camera.get_state(),
+                        whip: camera.get_whip_status(),
+                        srt: camera.get_srt_status(),
+                        control: auth2
+                            .as_ref()
+                            .and_then(|auth| auth.control(&headers, &uri)),
+                    })
+                }),
             )
             .route(
                 "/api/record",
-                post(|extract::Json(payload): extract::Json<bool>| async move {
-                    if payload {
-                        camera4.start_capture().await;
+                post(
+                    |State(camera): Camera,
+                     extract::Json(payload): extract::Json<bool>| async move {
+                    let started = if payload {
+                        camera.start_capture().await
                     } else {
-                        camera4.start_livefeed().await;
-                    }
+                        camera.start_livefeed().await
+                    };
 
-                    Json(camera4.get_state().await)
+                    match started {
+                        Ok(()) => Json(camera.get_state()).into_response(),
+                        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err).into_response(),
+                    }
                 }),
             )
             .route(
                 "/api/configuration",
-                get(|| async move { Json(camera.get_configuration().await) }).post(
-                    |extract::Json(payload): extract::Json<NullableConfiguration>| async move {
-                        camera3.set_configuration(payload).await;
-                        Json(camera3.get_configuration().await)
+                get(|State(camera): Camera| async move { Json(camera.get_configuration()) }).post(
+                    |State(camera): Camera,
+                     extract::Json(payload): extract::Json<NullableConfiguration>| async move {
+                        if let Err(err) = payload.validate() {
+                            return (StatusCode::BAD_REQUEST, err).into_response();
+                        }
+                        Json(camera.set_configuration(payload).await).into_response()
                     },
                 ),
             )
-            .layer(CorsLayer::permissive())
-            .fallback_service(ServeDir::new("frontend/dist"));
+            .with_state(actor.camera.clone())
+            .merge(actor.routes.clone());
+        let app = app.merge(actor.audit.router(actor.auth.clone()));
+        // The UI itself stays reachable, so it can ask for the password.
+        let app = match &actor.auth {
+            Some(auth) => app
+                .route_layer(middleware::from_fn_with_state(auth.clone(), auth::require))
+                .merge(auth.router()),
+            None => app,
+        }
+        .merge(actor.public_routes.clone())
+        // Outside of authentication, so turned away requests are logged too.
+        .layer(middleware::from_fn_with_state(
+            (actor.audit.clone(), actor.auth.clone()),
+            audit::record,
+        ))
+        .layer(auth::cors(&actor.cors_origins))
+        .fallback_service(ServeDir::new("frontend/dist"));
 
+        // webrtcsink only needs the signalling, anything else stays behind TLS.
+        let producer = actor.producer.take().map(|listener| {
+            axum::serve(
+                listener,
+                Router::new()
+                    .route_service("/signalling", app.clone())
+                    .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+        });
+        let producer = async move {
+            match producer {
+                Some(producer) => {
+                    let _ = producer.await;
+                }
+                None => std::future::pending().await,
+            }
+        };
         let listener: tokio::net::TcpListener =
             tokio::net::TcpListener::bind(actor.address).await.unwrap();
-        let server = axum::serve(listener, app);
+        let tls = actor.tls.clone();
+        let server = async {
+            match tls {
+                Some(acceptor) => {
+                    // Tapping makes axum hand out the client address as connect info.
+                    let listener = TlsListener::new(listener, acceptor).unwrap().tap_io(|_| {});
+                    axum::serve(
+                        listener,
+                        app.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .await
+                }
+                None => {
+                    axum::serve(
+                        listener,
+                        app.into_make_service_with_connect_info::<SocketAddr>(),
+                    )
+                    .await
+                }
+            }
+        };
+        let redirect = match actor.redirect.filter(|_| actor.tls.is_some()) {
+            Some(address) => Some(redirect_to_https(address, actor.address.port())),
+            None => None,
+        };
+        let redirect = async move {
+            match redirect {
+                Some(redirect) => redirect.await,
+                None => std::future::pending().await,
+            }
+        };
 
         tokio::select! {
             _ = server => {}
+            _ = redirect => {}
+            _ = producer => {}
             _ = async {
                 while let Some(message) = actor.receiver.recv().await {
                     actor.handle_message(message).await;
@@ -108,18 +379,67 @@ impl WebServerActor {
     }
 }
 
+/// Answers plain HTTP with a redirect to the same path on the HTTPS port.
+async fn redirect_to_https(address: SocketAddr, https_port: u16) {
+    let app = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        let host = headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())
+            .map_or_else(|| "localhost".to_string(), |host| host.host().to_string());
+        let path = uri.path_and_query().map_or("/", |path| path.as_str());
+        let location = if https_port == 443 {
+            format!("https://{host}{path}")
+        } else {
+            format!("https://{host}:{https_port}{path}")
+        };
+        Redirect::temporary(&location)
+    });
+
+    match tokio::net::TcpListener::bind(address).await {
+        Ok(listener) => {
+            let _ = axum::serve(listener, app).await;
+        }
+        Err(err) => {
+            warn!("Failed to listen for HTTP redirects on {address}: {err}");
+            // The web server keeps running without them.
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct WebServerActorHandle {
     sender: mpsc::Sender<WebServerActorMessage>,
 }
 
 impl WebServerActorHandle {
-    pub fn new(address: SocketAddr, camera: CameraActorHandle) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        address: SocketAddr,
+        camera: CameraActorHandle,
+        routes: Router,
+        public_routes: Router,
+        tls: Option<Acceptor>,
+        redirect: Option<SocketAddr>,
+        producer: Option<tokio::net::TcpListener>,
+        auth: Option<Auth>,
+        cors_origins: Vec<HeaderValue>,
+        audit: AuditLog,
+    ) -> Self {
         let (sender, receiver) = mpsc::channel(16);
         let actor = WebServerActor {
             receiver,
             address,
             camera,
+            routes,
+            public_routes,
+            tls,
+            redirect,
+            producer,
+            auth,
+            cors_origins,
+            audit,
         };
         tokio::spawn(WebServerActor::run(actor));
         Self { sender }
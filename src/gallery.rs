@@ -0,0 +1,35 @@
+use std::path::Path;
+
+use tracing::warn;
+
+/// The directory recordings are written to and served from.
+pub const GALLERY_DIR: &str = "gallery";
+
+/// Lists the video files in the gallery directory.
+pub async fn list() -> Vec<String> {
+    let dir = tokio::fs::read_dir(GALLERY_DIR).await;
+
+    if dir.is_err() {
+        warn!("failed to read gallery directory");
+        return vec![];
+    }
+
+    let mut dir = dir.unwrap();
+    let mut result = vec![];
+
+    while let Ok(Some(entry)) = dir.next_entry().await {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.ends_with(".mkv") || name.ends_with(".mov") || name.ends_with(".mp4") {
+            result.push(name);
+        }
+    }
+
+    result
+}
+
+pub fn path(name: &str) -> String {
+    Path::new(GALLERY_DIR)
+        .join(name)
+        .to_string_lossy()
+        .to_string()
+}
@@ -1,9 +1,15 @@
+#[cfg(target_os = "windows")]
 use tokio::task;
 
+use std::net::Ipv4Addr;
+
 use color_eyre::eyre::Result;
 use tracing::info;
 
 pub(crate) trait Hotspot {
+    /// The address the device has on its own hotspot.
+    const ADDRESS: Ipv4Addr;
+
     async fn start(&self) -> Result<()>;
 
     async fn stop(&self) -> Result<()>;
@@ -21,6 +27,9 @@ mod windows;
 #[cfg(target_os = "windows")]
 use windows::HotspotWindows as PlatformHotspot;
 
+/// The address devices connected to the hotspot reach the web server on.
+pub(crate) const ADDRESS: Ipv4Addr = <PlatformHotspot<'static> as Hotspot>::ADDRESS;
+
 struct HotspotActor {
     receiver: tokio::sync::mpsc::Receiver<HotspotMessage>,
     ssid: String,
@@ -80,6 +80,9 @@ impl<'a> HotspotLinux<'a> {
 }
 
 impl<'a> Hotspot for HotspotLinux<'a> {
+    // What NetworkManager gives shared connections.
+    const ADDRESS: std::net::Ipv4Addr = std::net::Ipv4Addr::new(10, 42, 0, 1);
+
     async fn start(&self) -> color_eyre::eyre::Result<()> {
         self.ensure_hotspot_exists().await?;
         
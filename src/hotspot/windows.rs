@@ -10,6 +10,9 @@ pub(crate) struct HotspotWindows<'a> {
 }
 
 impl<'a> Hotspot for HotspotWindows<'a> {
+    // What the mobile hotspot gives itself.
+    const ADDRESS: std::net::Ipv4Addr = std::net::Ipv4Addr::new(192, 168, 137, 1);
+
     async fn start(&self) -> color_eyre::eyre::Result<()> {
         let connection_profile = NetworkInformation::GetInternetConnectionProfile()?;
         let tethering_manager =
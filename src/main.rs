@@ -7,6 +7,10 @@ use clap::Parser;
 use color_eyre::eyre::Result;
 use tracing::{info, warn};
 
+mod audit;
+
+mod auth;
+
 mod camera;
 
 #[cfg(all(feature = "hotspot", not(target_os = "macos")))]
@@ -17,8 +21,15 @@ mod signalling;
 
 mod frontend;
 
+mod gallery;
+
+mod remote;
+
+mod tls;
+
 #[derive(Parser, Debug)]
 #[command(version, about)]
+#[command(group(clap::ArgGroup::new("https").args(["cert", "tls"]).multiple(true)))]
 struct Args {
     /// The network address and port to listen to.
     #[clap(short = 'a', long = "address", default_value = "0.0.0.0:8080")]
@@ -28,16 +39,100 @@ struct Args {
     #[clap(long)]
     enable_signalling: bool,
 
+    /// Whether signalling shares the port of the web server or has a listener of its own.
+    #[cfg(feature = "signalling")]
+    #[clap(long, value_enum, default_value_t)]
+    signalling_mode: signalling::SignallingMode,
+
     #[cfg(feature = "signalling")]
     #[clap(long, default_value = "0.0.0.0:8443")]
     signalling_address: std::net::SocketAddr,
 
-    /// TLS certificate to use
+    #[cfg(feature = "rtsp")]
+    #[clap(long)]
+    enable_rtsp: bool,
+
+    #[cfg(feature = "rtsp")]
+    #[clap(long, default_value = "0.0.0.0:8554")]
+    rtsp_address: std::net::SocketAddr,
+
+    /// RTSP mount point of the composed livefeed, which shows the left eye while recording.
+    #[cfg(feature = "rtsp")]
+    #[clap(long, default_value = "/live")]
+    rtsp_mount: String,
+
+    /// RTSP mount point of the left eye.
+    #[cfg(feature = "rtsp")]
+    #[clap(long, default_value = "/left")]
+    rtsp_left_mount: String,
+
+    /// RTSP mount point of the right eye.
+    #[cfg(feature = "rtsp")]
+    #[clap(long, default_value = "/right")]
+    rtsp_right_mount: String,
+
+    /// WHIP endpoint to publish the livefeed to. It gets the left eye while recording.
+    #[clap(long)]
+    whip_url: Option<String>,
+    /// Bearer token for the WHIP endpoint.
+    #[clap(long)]
+    whip_token: Option<String>,
+
+    /// Sends the livefeed over SRT, to `host:port` as caller or on `:port` as listener.
+    /// It gets the left eye while recording.
+    #[clap(long)]
+    srt_address: Option<String>,
+    #[clap(long, value_enum, default_value_t)]
+    srt_mode: camera::SrtMode,
+    #[clap(long, value_enum, default_value_t)]
+    srt_codec: camera::SrtCodec,
+    /// SRT receiver buffer in milliseconds.
+    #[clap(
+        long,
+        default_value_t = 125,
+        value_parser = clap::value_parser!(u32).range(..=camera::SRT_MAX_LATENCY as i64)
+    )]
+    srt_latency: u32,
+    /// Encrypts the SRT stream, 10 to 79 characters.
+    #[clap(long, value_parser = camera::parse_srt_passphrase)]
+    srt_passphrase: Option<String>,
+
+    #[clap(flatten)]
+    webrtc: camera::WebRtcSettings,
+
+    /// TLS certificate to use, as PKCS #12, or PEM with `--key`. Serves the web UI over HTTPS
+    /// when set, and is reloaded when the files change.
     #[clap(short, long)]
-    cert: Option<String>,
+    cert: Option<std::path::PathBuf>,
+    /// PEM key of the certificate.
+    #[clap(long, requires = "cert")]
+    key: Option<std::path::PathBuf>,
+    /// Serves HTTPS with a generated self-signed certificate when no `--cert` is given.
+    #[clap(long)]
+    tls: bool,
     /// password to TLS certificate
     #[clap(long)]
     cert_password: Option<String>,
+    /// Password for the web UI and token for scripts and signalling. Anyone on the network
+    /// can control the camera without it.
+    #[clap(long)]
+    auth_token: Option<String>,
+    /// Password for viewers, who can watch but not record or change the configuration.
+    #[clap(long, requires = "auth_token")]
+    viewer_token: Option<String>,
+    /// Origin that may use the API from another site, e.g. https://example.com. Can be
+    /// repeated. Any origin may when none is given.
+    #[clap(long = "cors-origin")]
+    cors_origins: Vec<axum::http::HeaderValue>,
+
+    /// Every request that changes something is appended to this JSON lines file.
+    #[clap(long, default_value = "audit.jsonl")]
+    audit_log: std::path::PathBuf,
+
+    /// Redirects plain HTTP on this address to HTTPS, e.g. 0.0.0.0:80. Needs `--cert` or
+    /// `--tls`.
+    #[clap(long, requires = "https")]
+    redirect_address: Option<std::net::SocketAddr>,
 
     #[cfg(all(feature = "hotspot", not(target_os = "macos")))]
     #[clap(long)]
@@ -61,23 +156,152 @@ async fn main() -> Result<()> {
 
     let shutdown = tokio::signal::ctrl_c();
 
-    let camera = CameraActorHandle::new();
+    let auth = args
+        .auth_token
+        .as_deref()
+        .map(|token| auth::Auth::new(token, args.viewer_token.as_deref()));
+    let audit = audit::AuditLog::new(args.audit_log.clone());
+    let peers = remote::PeerIdentities::default();
+    let (remote_sender, remote_receiver) = tokio::sync::mpsc::channel(16);
+
+    let mut webrtc = args.webrtc.clone();
+    webrtc.remote_control = Some(remote_sender);
+    #[allow(unused_mut)]
+    let mut routes = axum::Router::new();
+    // Reachable without logging in, such as the certificate devices have to trust first.
+    let mut public_routes = axum::Router::new();
+
+    let tls = match &args.cert {
+        Some(cert) => Some(
+            tls::CertificateFiles {
+                cert: cert.clone(),
+                key: args.key.clone(),
+                password: args.cert_password.clone(),
+            }
+            .watch()
+            .await?,
+        ),
+        None if args.tls => {
+            #[allow(unused_mut)]
+            let mut hotspot_addresses = Vec::new();
+            #[cfg(all(feature = "hotspot", not(target_os = "macos")))]
+            if args.enable_hotspot {
+                hotspot_addresses.push(hotspot::ADDRESS.into());
+            }
+            let self_signed = tls::self_signed(&args.address, &hotspot_addresses).await?;
+            public_routes = public_routes.merge(tls::certificate_router(self_signed.cert));
+            Some(self_signed.acceptor)
+        }
+        None => None,
+    };
+
+    // webrtcsink can't check the certificate, so with TLS it reaches the signalling of the
+    // web server over plain HTTP, on a port of its own that only listens on loopback.
+    #[cfg(feature = "signalling")]
+    let producer = if args.enable_signalling
+        && args.signalling_mode == signalling::SignallingMode::Web
+        && tls.is_some()
+    {
+        Some(tokio::net::TcpListener::bind((std::net::Ipv4Addr::LOCALHOST, 0)).await?)
+    } else {
+        None
+    };
+    #[cfg(not(feature = "signalling"))]
+    let producer = None;
+
+    #[cfg(feature = "signalling")]
+    if args.enable_signalling {
+        routes = routes.merge(signalling::router(
+            args.signalling_mode,
+            &args.signalling_address,
+            auth.clone(),
+            peers.clone(),
+        ));
+        if args.signalling_mode == signalling::SignallingMode::Web {
+            let address = match &producer {
+                Some(listener) => listener.local_addr()?,
+                None if args.address.ip().is_unspecified() => std::net::SocketAddr::new(
+                    std::net::Ipv4Addr::LOCALHOST.into(),
+                    args.address.port(),
+                ),
+                None => args.address,
+            };
+            webrtc.signaller_uri = Some(format!("ws://{address}/signalling"));
+            webrtc.signaller_token = auth.as_ref().map(|auth| auth.producer_token().to_string());
+        } else if args.auth_token.is_some() {
+            // Anyone could register as the camera or watch it there.
+            color_eyre::eyre::bail!(
+                "the standalone signalling server can't check the auth token, use --signalling-mode web"
+            );
+        }
+    }
+
+    let camera = CameraActorHandle::new(webrtc);
     let c3 = camera.clone();
 
-    let webserver = WebServerActorHandle::new(args.address, camera.clone());
+    let webserver = WebServerActorHandle::new(
+        args.address,
+        camera.clone(),
+        routes,
+        public_routes,
+        tls.clone(),
+        args.redirect_address,
+        producer,
+        auth.clone(),
+        args.cors_origins.clone(),
+        audit.clone(),
+    );
+    tokio::spawn(remote::serve(
+        remote_receiver,
+        camera.clone(),
+        auth,
+        peers,
+        audit,
+    ));
 
-    c3.start_livefeed().await;
+    // Already reported by the camera, the web UI can try again.
+    if let Err(err) = c3.start_livefeed().await {
+        warn!("Failed to start the livefeed: {err}");
+    }
+
+    if args.whip_url.is_some() {
+        c3.set_whip(camera::WhipSettings {
+            url: args.whip_url.clone(),
+            token: args.whip_token.clone(),
+        })
+        .await;
+    }
+
+    if let Some(address) = &args.srt_address {
+        c3.set_srt(Some(camera::SrtSettings {
+            address: address.clone(),
+            mode: args.srt_mode,
+            codec: args.srt_codec,
+            latency: args.srt_latency,
+            passphrase: args.srt_passphrase.clone(),
+        }))
+        .await;
+    }
+
+    #[cfg(feature = "rtsp")]
+    let _rtsp = if args.enable_rtsp {
+        Some(camera.start_rtsp(&camera::RtspSettings {
+            address: args.rtsp_address,
+            composed: args.rtsp_mount.clone(),
+            left: args.rtsp_left_mount.clone(),
+            right: args.rtsp_right_mount.clone(),
+        })?)
+    } else {
+        None
+    };
 
     #[cfg(feature = "signalling")]
     {
-        if args.enable_signalling {
+        if args.enable_signalling && args.signalling_mode == signalling::SignallingMode::Standalone
+        {
             tokio::spawn(async move {
-                if let Err(err) = signalling::run_signalling_server(
-                    &args.signalling_address,
-                    &args.cert,
-                    &args.cert_password,
-                )
-                .await
+                if let Err(err) =
+                    signalling::run_signalling_server(&args.signalling_address, tls).await
                 {
                     warn!("signalling server error: {:?}", err);
                 }
@@ -132,3 +356,18 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirects_only_to_https() {
+        let parse = |args: &[&str]| Args::try_parse_from([&["capture"], args].concat());
+
+        assert!(parse(&["--redirect-address", "0.0.0.0:80"]).is_err());
+        assert!(parse(&["--redirect-address", "0.0.0.0:80", "--tls"]).is_ok());
+        assert!(parse(&["--redirect-address", "0.0.0.0:80", "--cert", "cert.p12"]).is_ok());
+        assert!(parse(&["--tls", "--cert", "cert.p12"]).is_ok());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use axum::http::StatusCode;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::mpsc;
+
+use crate::audit::{AuditEntry, AuditLog};
+use crate::auth::{Auth, Identity};
+use crate::camera::{
+    CameraActorHandle, CameraState, NullableConfiguration, RemoteControl, RemoteRequest,
+};
+
+/// Who connected to signalling as which peer, so requests over the control data channel
+/// can be checked like the REST API's.
+pub(crate) type PeerIdentities = Arc<Mutex<HashMap<String, PeerIdentity>>>;
+
+#[derive(Clone)]
+pub(crate) struct PeerIdentity {
+    /// None without authentication. Checked again for every request, as the session
+    /// may have ended or the lock changed hands since the peer connected.
+    pub identity: Option<Identity>,
+    pub address: SocketAddr,
+}
+
+/// Carries out the requests viewers send over the control data channel.
+pub(crate) async fn serve(
+    mut requests: mpsc::Receiver<RemoteControl>,
+    camera: CameraActorHandle,
+    auth: Option<Auth>,
+    peers: PeerIdentities,
+    audit: AuditLog,
+) {
+    // Taken from the queue while merging nudges, but not a nudge of the same peer.
+    let mut next = None;
+    loop {
+        let mut control = match next.take() {
+            Some(control) => control,
+            None => match requests.recv().await {
+                Some(control) => control,
+                None => break,
+            },
+        };
+
+        // A gamepad stick sends nudges continuously, they are carried out and logged as one.
+        let mut merged = Vec::new();
+        if let RemoteRequest::NudgeConvergence { mut x, mut y } = control.request {
+            while let Ok(queued) = requests.try_recv() {
+                match queued.request {
+                    RemoteRequest::NudgeConvergence { x: dx, y: dy }
+                        if queued.peer_id == control.peer_id =>
+                    {
+                        x += dx;
+                        y += dy;
+                        merged.push(queued);
+                    }
+                    _ => {
+                        next = Some(queued);
+                        break;
+                    }
+                }
+            }
+            control.request = RemoteRequest::NudgeConvergence { x, y };
+        }
+
+        let peer = peers.lock().unwrap().get(&control.peer_id).cloned();
+        let identity = match &auth {
+            Some(auth) => peer
+                .as_ref()
+                .and_then(|peer| peer.identity.as_ref())
+                .and_then(|identity| auth.refresh(identity)),
+            None => None,
+        };
+        let identity = identity.as_ref();
+        let allowed = match &auth {
+            Some(auth) => identity.is_some_and(|identity| auth.may_control(identity)),
+            None => true,
+        };
+
+        let result = if allowed {
+            handle(&camera, &control.request).await
+        } else {
+            Err("not allowed to control the camera".into())
+        };
+
+        for merged in merged {
+            merged.respond(result.clone());
+        }
+        audit
+            .append(AuditEntry {
+                timestamp: OffsetDateTime::now_utc()
+                    .format(&Rfc3339)
+                    .unwrap_or_default(),
+                client: peer.as_ref().map(|peer| peer.address),
+                role: identity.map(|identity| identity.role),
+                session: identity
+                    .and_then(|identity| identity.session.as_ref())
+                    .map(|session| session.chars().take(8).collect()),
+                method: "DATACHANNEL".into(),
+                path: "control".into(),
+                body: serde_json::to_value(&control.request).ok(),
+                status: match (&result, allowed) {
+                    (Ok(()), _) => StatusCode::OK,
+                    (Err(_), false) => StatusCode::FORBIDDEN,
+                    (Err(_), true) => StatusCode::INTERNAL_SERVER_ERROR,
+                }
+                .as_u16(),
+                takeover: None,
+            })
+            .await;
+
+        control.respond(result);
+    }
+}
+
+async fn handle(camera: &CameraActorHandle, request: &RemoteRequest) -> Result<(), String> {
+    match *request {
+        RemoteRequest::NudgeConvergence { x, y } => {
+            let (current_x, current_y) = camera.get_configuration().convergence;
+            camera
+                .set_configuration(NullableConfiguration {
+                    convergence: Some((
+                        (current_x + x).clamp(-1.0, 1.0),
+                        (current_y + y).clamp(-1.0, 1.0),
+                    )),
+                    ..Default::default()
+                })
+                .await;
+            Ok(())
+        }
+        RemoteRequest::Record { enabled } => {
+            let enabled = enabled.unwrap_or(camera.get_state() != CameraState::Capture);
+            if enabled {
+                camera.start_capture().await
+            } else {
+                camera.start_livefeed().await
+            }
+        }
+        RemoteRequest::MultiviewMode { mode } => {
+            camera
+                .set_configuration(NullableConfiguration {
+                    multiview_mode: Some(mode),
+                    ..Default::default()
+                })
+                .await;
+            Ok(())
+        }
+    }
+}
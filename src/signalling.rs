@@ -1,51 +1,51 @@
 // Abridged from https://gitlab.freedesktop.org/gstreamer/gst-plugins-rs/-/blob/main/net/webrtc/signalling/src/bin/server.rs
 
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo,
+    },
+    http::{HeaderMap, Uri},
+    routing::get,
+    Json, Router,
+};
 use color_eyre::eyre::Result;
 use gst_plugin_webrtc_signalling::server::Server;
 use gst_plugin_webrtc_signalling::{handlers::Handler, server::ServerError};
-use tokio::io::AsyncReadExt;
+use gst_plugin_webrtc_signalling_protocol::IncomingMessage;
+use serde::Serialize;
 use tokio::net::TcpListener;
-use tokio::{fs, task};
-use tokio_native_tls::native_tls;
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
 use tracing::{debug, warn};
 
-const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+use crate::auth::{Auth, Role};
+use crate::remote::{PeerIdentities, PeerIdentity};
+use crate::tls::{Acceptor, TLS_HANDSHAKE_TIMEOUT};
+/// Idle connections get pinged, like the standalone server does.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
 
 pub(crate) async fn run_signalling_server(
     addr: &std::net::SocketAddr,
-    cert: &Option<String>,
-    cert_password: &Option<String>,
+    acceptor: Option<Acceptor>,
 ) -> Result<()> {
     let server = Server::spawn(Handler::new);
 
     // Create the event loop and TCP listener we'll accept connections on.
     let listener = TcpListener::bind(&addr).await?;
 
-    let acceptor = match cert {
-        Some(cert) => {
-            let mut file = fs::File::open(cert).await?;
-            let mut identity = vec![];
-            file.read_to_end(&mut identity).await?;
-            let identity = tokio_native_tls::native_tls::Identity::from_pkcs12(
-                &identity,
-                cert_password.as_deref().unwrap_or(""),
-            )?;
-            Some(tokio_native_tls::TlsAcceptor::from(
-                native_tls::TlsAcceptor::new(identity)?,
-            ))
-        }
-        None => None,
-    };
-
     debug!("Listening on: {}", addr);
 
     while let Ok((stream, address)) = listener.accept().await {
         let mut server_clone = server.clone();
         debug!("Accepting connection from {}", address);
 
-        if let Some(acceptor) = acceptor.clone() {
+        if let Some(acceptor) = acceptor.as_ref().map(|acceptor| acceptor.borrow().clone()) {
             tokio::spawn(async move {
                 match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
                     Ok(Ok(stream)) => server_clone.accept_async(stream).await,
@@ -66,3 +66,160 @@ pub(crate) async fn run_signalling_server(
 
     Ok(())
 }
+
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SignallingMode {
+    /// On `/signalling` of the web server, so one port covers everything.
+    #[default]
+    Web,
+    /// On a listener of its own, at `signalling_address`.
+    Standalone,
+}
+
+/// Tells the web UI where to find the signalling server.
+#[derive(Copy, Clone, Serialize)]
+struct SignallingInfo {
+    mode: SignallingMode,
+    /// Only set for the standalone server, the web server's port is known already.
+    port: Option<u16>,
+}
+
+/// The routes the web server needs for signalling in the given mode.
+pub(crate) fn router(
+    mode: SignallingMode,
+    standalone_address: &std::net::SocketAddr,
+    auth: Option<Auth>,
+    identities: PeerIdentities,
+) -> Router {
+    let info = SignallingInfo {
+        mode,
+        port: (mode == SignallingMode::Standalone).then_some(standalone_address.port()),
+    };
+    let router = Router::new().route("/api/signalling", get(move || async move { Json(info) }));
+
+    match mode {
+        SignallingMode::Web => {
+            let hub = Hub::spawn(identities);
+            router.route(
+                "/signalling",
+                get(
+                    move |upgrade: WebSocketUpgrade,
+                          ConnectInfo(address): ConnectInfo<std::net::SocketAddr>,
+                          headers: HeaderMap,
+                          uri: Uri| async move {
+                        let identity = auth.as_ref().and_then(|auth| auth.identify(&headers, &uri));
+                        // Viewers must not be able to pose as the camera.
+                        let may_produce = auth.is_none()
+                            || identity
+                                .as_ref()
+                                .is_some_and(|identity| identity.role == Role::Producer);
+                        let peer = PeerIdentity { identity, address };
+                        upgrade.on_upgrade(move |socket| hub.serve(socket, peer, may_produce))
+                    },
+                ),
+            )
+        }
+        SignallingMode::Standalone => router,
+    }
+}
+
+/// What `Server` does for the standalone listener, on WebSockets upgraded by axum.
+#[derive(Clone)]
+struct Hub {
+    incoming: mpsc::Sender<(String, Option<IncomingMessage>)>,
+    peers: Arc<Mutex<HashMap<String, mpsc::Sender<String>>>>,
+    identities: PeerIdentities,
+}
+
+impl Hub {
+    fn spawn(identities: PeerIdentities) -> Self {
+        let (incoming, receiver) = mpsc::channel(1000);
+        let peers: Arc<Mutex<HashMap<String, mpsc::Sender<String>>>> = Default::default();
+
+        let mut handler = Handler::new(Box::pin(ReceiverStream::new(receiver)));
+        let handler_peers = peers.clone();
+        task::spawn(async move {
+            while let Some((peer_id, message)) = handler.next().await {
+                let message = match serde_json::to_string(&message) {
+                    Ok(message) => message,
+                    Err(err) => {
+                        warn!("Failed to serialize outgoing message: {}", err);
+                        continue;
+                    }
+                };
+                // A peer that doesn't keep up must not hold up signalling for everyone else.
+                // Dropping its sender ends its connection once the queue is drained.
+                let mut peers = handler_peers.lock().unwrap();
+                if let Some(sender) = peers.get(&peer_id) {
+                    if let Err(mpsc::error::TrySendError::Full(_)) = sender.try_send(message) {
+                        warn!("Signalling peer {} fell behind, disconnecting it", peer_id);
+                        peers.remove(&peer_id);
+                    }
+                }
+            }
+        });
+
+        Self {
+            incoming,
+            peers,
+            identities,
+        }
+    }
+
+    async fn serve(self, mut socket: WebSocket, identity: PeerIdentity, may_produce: bool) {
+        let peer_id = format!("{:032x}", rand::random::<u128>());
+        debug!("New signalling peer {}", peer_id);
+        self.identities
+            .lock()
+            .unwrap()
+            .insert(peer_id.clone(), identity);
+
+        let (sender, mut outgoing) = mpsc::channel::<String>(1000);
+        self.peers.lock().unwrap().insert(peer_id.clone(), sender);
+        let _ = self
+            .incoming
+            .send((peer_id.clone(), Some(IncomingMessage::NewPeer)))
+            .await;
+
+        let mut keepalive = tokio::time::interval(KEEPALIVE_INTERVAL);
+        loop {
+            tokio::select! {
+                message = outgoing.recv() => {
+                    let Some(message) = message else { break };
+                    if socket.send(Message::Text(message.into())).await.is_err() {
+                        break;
+                    }
+                }
+                message = socket.recv() => match message {
+                    Some(Ok(Message::Text(message))) => {
+                        match serde_json::from_str::<IncomingMessage>(&message) {
+                            Ok(IncomingMessage::SetPeerStatus(status)) if status.producing() && !may_produce => {
+                                warn!("Signalling peer {} may not register as producer", peer_id);
+                                break;
+                            }
+                            Ok(message) => {
+                                let _ = self.incoming.send((peer_id.clone(), Some(message))).await;
+                            }
+                            Err(err) => {
+                                warn!("Failed to parse incoming message: {} ({})", err, message.as_str());
+                            }
+                        }
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                },
+                _ = keepalive.tick() => {
+                    if socket.send(Message::Ping(Default::default())).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        debug!("Signalling peer {} left", peer_id);
+        let _ = self.incoming.send((peer_id.clone(), None)).await;
+        self.peers.lock().unwrap().remove(&peer_id);
+        self.identities.lock().unwrap().remove(&peer_id);
+    }
+}
@@ -0,0 +1,341 @@
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use axum::{http::header, routing::get, Router};
+use color_eyre::eyre::Result;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::{fs, task};
+use tokio_native_tls::{native_tls, TlsAcceptor, TlsStream};
+use tracing::{debug, info, warn};
+
+pub(crate) const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where the generated certificate is kept, so devices only have to trust it once.
+const CERTIFICATE_DIR: &str = "certificate";
+const CERTIFICATE_FILE: &str = "cert.pem";
+const KEY_FILE: &str = "key.pem";
+/// The names the certificate is valid for, one per line.
+const NAMES_FILE: &str = "names.txt";
+/// Apple doesn't accept server certificates that are valid for more than 825 days.
+const CERTIFICATE_VALIDITY: time::Duration = time::Duration::days(800);
+/// Renewed a while before it expires, devices have to trust the new one again.
+const CERTIFICATE_RENEWAL: Duration = Duration::from_secs(700 * 24 * 60 * 60);
+
+/// The current certificate, replaced when it's reloaded.
+/// Connections keep the certificate they were accepted with.
+pub(crate) type Acceptor = watch::Receiver<TlsAcceptor>;
+
+/// A certificate passed with `--cert`, either PKCS #12 or PEM with `--key`.
+#[derive(Clone, Debug)]
+pub(crate) struct CertificateFiles {
+    pub cert: PathBuf,
+    /// PEM encoded PKCS #8 key. `cert` is PKCS #12 without it.
+    pub key: Option<PathBuf>,
+    /// Unlocks the PKCS #12 file.
+    pub password: Option<String>,
+}
+
+impl CertificateFiles {
+    async fn load(&self) -> Result<TlsAcceptor> {
+        let cert = fs::read(&self.cert).await?;
+        match &self.key {
+            Some(key) => pem_acceptor(&cert, &fs::read(key).await?),
+            None => {
+                let password = self.password.as_deref().unwrap_or("");
+                let identity = native_tls::Identity::from_pkcs12(&cert, password)?;
+                Ok(TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?))
+            }
+        }
+    }
+
+    async fn modified(&self) -> Option<(SystemTime, Option<SystemTime>)> {
+        let cert = fs::metadata(&self.cert).await.ok()?.modified().ok()?;
+        let key = match &self.key {
+            Some(key) => Some(fs::metadata(key).await.ok()?.modified().ok()?),
+            None => None,
+        };
+        Some((cert, key))
+    }
+
+    /// Loads the certificate, and reloads it whenever the files change.
+    pub(crate) async fn watch(self) -> Result<Acceptor> {
+        let mut modified = self.modified().await;
+        let (sender, acceptor) = watch::channel(self.load().await?);
+
+        task::spawn(async move {
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            while !sender.is_closed() {
+                interval.tick().await;
+                let current = self.modified().await;
+                if current.is_none() || current == modified {
+                    continue;
+                }
+                // Tried again on the next tick if the files were only partly written.
+                match self.load().await {
+                    Ok(acceptor) => {
+                        info!("reloaded the TLS certificate {}", self.cert.display());
+                        modified = current;
+                        sender.send_replace(acceptor);
+                    }
+                    Err(err) => warn!("Failed to reload the TLS certificate: {}", err),
+                }
+            }
+        });
+
+        Ok(acceptor)
+    }
+}
+
+fn pem_acceptor(cert: &[u8], key: &[u8]) -> Result<TlsAcceptor> {
+    let identity = native_tls::Identity::from_pkcs8(cert, key)?;
+    Ok(TlsAcceptor::from(native_tls::TlsAcceptor::new(identity)?))
+}
+
+/// A certificate generated for the device, for when nobody brought one.
+pub(crate) struct SelfSigned {
+    /// PEM, for devices to install and trust.
+    pub cert: String,
+    pub acceptor: Acceptor,
+}
+
+/// Loads the self-signed certificate of an earlier run, or generates one. It's generated
+/// again when the names devices may use have changed, such as when the hotspot is enabled.
+pub(crate) async fn self_signed(
+    address: &SocketAddr,
+    hotspot_addresses: &[IpAddr],
+) -> Result<SelfSigned> {
+    let dir = Path::new(CERTIFICATE_DIR);
+    let cert_path = dir.join(CERTIFICATE_FILE);
+    let key_path = dir.join(KEY_FILE);
+    let names_path = dir.join(NAMES_FILE);
+    let names = local_names(address, hotspot_addresses);
+
+    let fresh = fs::metadata(&cert_path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| {
+            modified
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed < CERTIFICATE_RENEWAL)
+        });
+    let same_names = fs::read_to_string(&names_path)
+        .await
+        .is_ok_and(|stored| stored.lines().eq(names.iter().map(String::as_str)));
+    // Anyone who could read the key could have signed certificates for the names.
+    if fresh && same_names && key_is_private(&key_path).await {
+        if let (Ok(cert), Ok(key)) = (
+            fs::read_to_string(&cert_path).await,
+            fs::read_to_string(&key_path).await,
+        ) {
+            let (_, acceptor) = watch::channel(pem_acceptor(cert.as_bytes(), key.as_bytes())?);
+            return Ok(SelfSigned { cert, acceptor });
+        }
+    }
+
+    info!(
+        "generating a self-signed certificate for {}",
+        names.join(", ")
+    );
+
+    let mut params = rcgen::CertificateParams::new(names.clone())?;
+    params
+        .distinguished_name
+        .push(rcgen::DnType::CommonName, "capture");
+    // Devices only offer to trust certificate authorities. Constrained to the names of
+    // the device, so it can't vouch for any other site on the devices that trust it.
+    params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Constrained(0));
+    params.name_constraints = Some(rcgen::NameConstraints {
+        permitted_subtrees: names
+            .iter()
+            .map(|name| match name.parse::<IpAddr>() {
+                Ok(ip) => rcgen::GeneralSubtree::IpAddress(rcgen::CidrSubnet::from_addr_prefix(
+                    ip,
+                    if ip.is_ipv4() { 32 } else { 128 },
+                )),
+                Err(_) => rcgen::GeneralSubtree::DnsName(name.clone()),
+            })
+            .collect(),
+        excluded_subtrees: vec![],
+    });
+    params.key_usages = vec![
+        rcgen::KeyUsagePurpose::DigitalSignature,
+        rcgen::KeyUsagePurpose::KeyCertSign,
+    ];
+    params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth];
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::days(1);
+    params.not_after = now + CERTIFICATE_VALIDITY;
+
+    let key_pair = rcgen::KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?.pem();
+    let key = key_pair.serialize_pem();
+
+    fs::create_dir_all(dir).await?;
+    fs::write(&cert_path, &cert).await?;
+    fs::write(&names_path, names.join("\n")).await?;
+    // Replaced rather than truncated, so an existing file doesn't keep its permissions.
+    let _ = fs::remove_file(&key_path).await;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options
+        .open(&key_path)
+        .await?
+        .write_all(key.as_bytes())
+        .await?;
+
+    let (_, acceptor) = watch::channel(pem_acceptor(cert.as_bytes(), key.as_bytes())?);
+    Ok(SelfSigned { cert, acceptor })
+}
+
+/// Whether only the owner can read the key.
+#[cfg(unix)]
+async fn key_is_private(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    fs::metadata(path)
+        .await
+        .is_ok_and(|metadata| metadata.permissions().mode() & 0o077 == 0)
+}
+
+#[cfg(not(unix))]
+async fn key_is_private(_path: &Path) -> bool {
+    true
+}
+
+/// The names and addresses devices may use to reach the web server.
+fn local_names(address: &SocketAddr, hotspot_addresses: &[IpAddr]) -> Vec<String> {
+    let mut names = vec!["localhost".to_string(), "127.0.0.1".to_string()];
+    names.extend(hotspot_addresses.iter().map(|address| address.to_string()));
+    if !address.ip().is_unspecified()
+        && !address.ip().is_loopback()
+        && !hotspot_addresses.contains(&address.ip())
+    {
+        names.push(address.ip().to_string());
+    }
+
+    let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|hostname| hostname.trim().to_lowercase())
+        .filter(|hostname| !hostname.is_empty() && hostname != "localhost");
+    if let Some(hostname) = hostname {
+        names.push(format!("{hostname}.local"));
+        names.push(hostname);
+    }
+
+    names
+}
+
+/// Serves the self-signed certificate, so devices can install and trust it.
+pub(crate) fn certificate_router(cert: String) -> Router {
+    Router::new().route(
+        "/api/certificate",
+        get(move || async move {
+            (
+                [
+                    (header::CONTENT_TYPE, "application/x-x509-ca-cert"),
+                    (
+                        header::CONTENT_DISPOSITION,
+                        "attachment; filename=\"capture.crt\"",
+                    ),
+                ],
+                cert,
+            )
+        }),
+    )
+}
+
+/// Terminates TLS for axum.
+/// Handshakes run in tasks of their own, so a slow client doesn't hold up the others.
+pub(crate) struct TlsListener {
+    local_addr: SocketAddr,
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+}
+
+impl TlsListener {
+    pub(crate) fn new(listener: TcpListener, acceptor: Acceptor) -> io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, connections) = mpsc::channel(16);
+
+        task::spawn(async move {
+            while !sender.is_closed() {
+                let (stream, address) = match listener.accept().await {
+                    Ok(connection) => connection,
+                    Err(err) => {
+                        warn!("Failed to accept connection: {}", err);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+
+                let acceptor = acceptor.borrow().clone();
+                let sender = sender.clone();
+                task::spawn(async move {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => {
+                            let _ = sender.send((stream, address)).await;
+                        }
+                        Ok(Err(err)) => {
+                            debug!("Failed to accept TLS connection from {}: {}", address, err)
+                        }
+                        Err(_) => debug!("TLS connection from {} timed out", address),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            local_addr,
+            connections,
+        })
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // The accept loop only ends with the listener.
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_the_listen_and_hotspot_addresses() {
+        let hotspot = [IpAddr::from([10, 42, 0, 1])];
+
+        let names = local_names(&"0.0.0.0:8080".parse().unwrap(), &[]);
+        assert_eq!(names[..2], ["localhost", "127.0.0.1"]);
+        assert!(!names.contains(&"0.0.0.0".to_string()));
+
+        let names = local_names(&"192.168.1.20:8080".parse().unwrap(), &hotspot);
+        assert_eq!(
+            names[..4],
+            ["localhost", "127.0.0.1", "10.42.0.1", "192.168.1.20"]
+        );
+
+        // Listening on the hotspot doesn't name it twice.
+        let names = local_names(&"10.42.0.1:8080".parse().unwrap(), &hotspot);
+        assert_eq!(names.iter().filter(|name| *name == "10.42.0.1").count(), 1);
+    }
+}